@@ -9,8 +9,8 @@ TOKEN=""
 ENDPOINT=""
 INTERVAL="3"
 FLAG="🖥️"
+AUTO_UPDATE="0"
 AGENT_BIN="$INSTALL_DIR/agent"
-LOADER="$INSTALL_DIR/ld-musl-x86_64.so.1"
 
 function log() {
   echo -e "[install] $1"
@@ -18,7 +18,7 @@ function log() {
 
 function usage() {
   cat <<USAGE
-用法: bash install.sh --token=TOKEN [--endpoint=https://host] [--interval=秒] [--flag=Emoji]
+用法: bash install.sh --token=TOKEN [--endpoint=https://host] [--interval=秒] [--flag=Emoji] [--auto-update]
 USAGE
 }
 
@@ -32,6 +32,7 @@ while [[ $# -gt 0 ]]; do
     --interval) shift; INTERVAL="$1" ;;
     --flag=*) FLAG="${1#*=}" ;;
     --flag) shift; FLAG="$1" ;;
+    --auto-update) AUTO_UPDATE="1" ;;
     -h|--help) usage; exit 0 ;;
   esac
   shift || true
@@ -51,6 +52,16 @@ if [[ "$EUID" -ne 0 ]]; then
   exit 1
 fi
 
+case "$(uname -m)" in
+  x86_64|amd64) ARCH="x86_64"; LOADER_NAME="ld-musl-x86_64.so.1" ;;
+  aarch64|arm64) ARCH="aarch64"; LOADER_NAME="ld-musl-aarch64.so.1" ;;
+  armv7l|armv7|armhf) ARCH="armv7"; LOADER_NAME="ld-musl-armhf.so.1" ;;
+  riscv64) ARCH="riscv64"; LOADER_NAME="ld-musl-riscv64.so.1" ;;
+  *) echo "不支持的架构：$(uname -m)" >&2; exit 1 ;;
+esac
+LOADER="$INSTALL_DIR/$LOADER_NAME"
+
+log "系统架构：$ARCH"
 log "安装目录：$INSTALL_DIR"
 mkdir -p "$INSTALL_DIR"
 if ! touch "$INSTALL_DIR/.write_test" 2>/dev/null; then
@@ -63,16 +74,34 @@ TMPDIR="$(mktemp -d)"
 trap 'rm -rf "$TMPDIR"' EXIT
 
 log "下载 Agent 二进制"
-TMP_AGENT="$TMPDIR/agent.bin"
-curl -fSL --retry 3 --retry-delay 1 "$ENDPOINT/agent.bin" -o "$TMP_AGENT"
-install -m 0755 "$TMP_AGENT" "$AGENT_BIN"
+TMP_AGENT="$TMPDIR/agent-$ARCH"
+if ! curl -fSL --retry 3 --retry-delay 1 "$ENDPOINT/agent/$ARCH" -o "$TMP_AGENT"; then
+  echo "面板未提供 $ARCH 架构的 Agent" >&2
+  exit 1
+fi
 log "下载运行时 (musl loader)"
-TMP_LOADER="$TMPDIR/ld-musl-x86_64.so.1"
-curl -fSL --retry 3 --retry-delay 1 "$ENDPOINT/ld-musl-x86_64.so.1" -o "$TMP_LOADER"
-install -m 0755 "$TMP_LOADER" "$LOADER"
-if [ ! -f /lib/ld-musl-x86_64.so.1 ]; then
-  log "复制 musl loader 到 /lib"
-  cp "$LOADER" /lib/ld-musl-x86_64.so.1
+TMP_LOADER="$TMPDIR/$LOADER_NAME"
+CHECK_PATTERN="agent-$ARCH"
+# 静态链接的构建没有 loader
+if curl -fsSL --retry 3 --retry-delay 1 "$ENDPOINT/agent/$ARCH/loader" -o "$TMP_LOADER"; then
+  CHECK_PATTERN="$CHECK_PATTERN|$LOADER_NAME"
+else
+  log "面板未提供 $LOADER_NAME，按静态构建安装"
+  rm -f "$TMP_LOADER"
+fi
+log "校验 SHA-256"
+curl -fsSL --retry 3 --retry-delay 1 "$ENDPOINT/agent/SHA256SUMS" -o "$TMPDIR/SHA256SUMS"
+if ! (cd "$TMPDIR" && grep -E "  ($CHECK_PATTERN)\$" SHA256SUMS | sha256sum -c --status -); then
+  echo "校验失败，已中止安装" >&2
+  exit 1
+fi
+install -m 0755 "$TMP_AGENT" "$AGENT_BIN"
+if [ -f "$TMP_LOADER" ]; then
+  install -m 0755 "$TMP_LOADER" "$LOADER"
+  if [ ! -f "/lib/$LOADER_NAME" ]; then
+    log "复制 musl loader 到 /lib"
+    cp "$LOADER" "/lib/$LOADER_NAME"
+  fi
 fi
 rm -rf "$INSTALL_DIR/venv" "$INSTALL_DIR/agent.py"
 
@@ -81,6 +110,7 @@ IMONITOR_TOKEN=$TOKEN
 IMONITOR_ENDPOINT=$ENDPOINT
 IMONITOR_INTERVAL=$INTERVAL
 IMONITOR_FLAG=$FLAG
+IMONITOR_AUTO_UPDATE=$AUTO_UPDATE
 EOF_ENV
 
 AGENT_CMD="$AGENT_BIN --token=\$IMONITOR_TOKEN --endpoint=\$IMONITOR_ENDPOINT --interval=\$IMONITOR_INTERVAL --flag=\$IMONITOR_FLAG"
@@ -316,11 +346,13 @@ uninstall_agent() {
   rm -f /etc/systemd/system/${SERVICE_AGENT}.service
   systemctl daemon-reload
   local agent_dir="/opt/imonitor-agent"
-  local loader_sys="/lib/ld-musl-x86_64.so.1"
-  local loader_pkg="${agent_dir}/ld-musl-x86_64.so.1"
-  if [[ -f "$loader_sys" && -f "$loader_pkg" ]] && cmp -s "$loader_sys" "$loader_pkg"; then
-    rm -f "$loader_sys"
-  fi
+  local loader_pkg loader_sys
+  for loader_pkg in "${agent_dir}"/ld-musl-*.so.1; do
+    loader_sys="/lib/$(basename "$loader_pkg")"
+    if [[ -f "$loader_sys" && -f "$loader_pkg" ]] && cmp -s "$loader_sys" "$loader_pkg"; then
+      rm -f "$loader_sys"
+    fi
+  done
   pkill -f "${agent_dir}/agent" 2>/dev/null || true
   rm -rf "$agent_dir"
   if [[ $keep_cli -eq 0 ]]; then
@@ -0,0 +1,360 @@
+//! 告警规则：按节点上报的指标逐条评估阈值规则，并维护告警事件的开启与恢复。
+
+use std::path::Path;
+
+use axum::{
+    extract::{Path as AxumPath, State},
+    http::HeaderMap,
+    Json,
+};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{require_auth, AppError, AppState};
+
+pub(crate) async fn list_alerts_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let events = list_alert_events(&state.data_dir.join("imonitor.db"))?;
+    Ok(Json(json!({ "alerts": events })))
+}
+
+pub(crate) async fn list_alert_rules_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let conn = Connection::open(state.data_dir.join("imonitor.db"))?;
+    let rules = load_alert_rules(&conn, None)?;
+    Ok(Json(json!({ "rules": rules })))
+}
+
+pub(crate) async fn create_alert_rule_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateAlertRuleRequest>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let id = create_alert_rule(&state.data_dir.join("imonitor.db"), &payload)?;
+    Ok(Json(json!({"status": "created", "id": id})))
+}
+
+pub(crate) async fn delete_alert_rule_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxumPath(id): AxumPath<String>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    delete_alert_rule(&state.data_dir.join("imonitor.db"), &id)?;
+    Ok(Json(json!({"status": "deleted"})))
+}
+
+/// 列表型指标：规则里的 `mount.used_percent` 对应 metrics.mounts 中 `mount == target` 的条目；
+/// 其它带 `.` 的指标按嵌套对象取值。
+const LIST_METRICS: &[(&str, &str, &str)] = &[
+    ("mount", "mounts", "mount"),
+    ("interface", "interfaces", "name"),
+    ("disk", "disk_io", "device"),
+    ("temperature", "temperatures", "sensor"),
+    ("container", "containers", "name"),
+];
+
+#[derive(Serialize)]
+struct AlertRule {
+    id: String,
+    node_token: Option<String>,
+    metric: String,
+    target: Option<String>,
+    op: String,
+    threshold: f64,
+    created_at: f64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateAlertRuleRequest {
+    #[serde(default)]
+    node_token: Option<String>,
+    metric: String,
+    #[serde(default)]
+    target: Option<String>,
+    #[serde(default)]
+    op: Option<String>,
+    threshold: f64,
+}
+
+#[derive(Serialize)]
+struct AlertEvent {
+    id: i64,
+    rule_id: String,
+    node_token: String,
+    metric: String,
+    target: Option<String>,
+    value: Option<f64>,
+    threshold: f64,
+    fired_at: f64,
+    resolved_at: Option<f64>,
+}
+
+pub(crate) fn resolve_metric(metrics: &Map<String, Value>, metric: &str, target: Option<&str>) -> Option<f64> {
+    // 自定义指标名本身可能含 `.`，整体作为键
+    if let Some(name) = metric.strip_prefix("custom.") {
+        return metrics.get("custom")?.get(name)?.as_f64();
+    }
+    if let Some((prefix, field)) = metric.split_once('.') {
+        let Some((_, list_key, id_key)) = LIST_METRICS.iter().find(|(p, _, _)| *p == prefix) else {
+            // 非列表分组按嵌套对象逐级取值，如 cpu_breakdown.steal、pressure.io.some.avg10；
+            // 缺失的可选指标（如旧内核没有 PSI）视为无数据，不触发告警
+            let mut value = metrics.get(prefix)?;
+            for key in field.split('.') {
+                value = value.get(key)?;
+            }
+            return value.as_f64();
+        };
+        let target = target?;
+        let item = metrics
+            .get(*list_key)?
+            .as_array()?
+            .iter()
+            .find(|item| item.get(*id_key).and_then(Value::as_str) == Some(target))?;
+        return item.get(field)?.as_f64();
+    }
+    metrics.get(metric)?.as_f64()
+}
+
+pub(crate) fn evaluate_alerts(
+    conn: &Connection,
+    token: &str,
+    metrics: &Map<String, Value>,
+    now: f64,
+) -> Result<(), AppError> {
+    let rules = load_alert_rules(conn, Some(token))?;
+    for rule in rules {
+        let value = resolve_metric(metrics, &rule.metric, rule.target.as_deref());
+        let breached = match value {
+            Some(v) if rule.op == "<" => v < rule.threshold,
+            Some(v) => v > rule.threshold,
+            None => false,
+        };
+        transition_alert(
+            conn,
+            AlertCheck {
+                rule_id: &rule.id,
+                token,
+                metric: &rule.metric,
+                target: rule.target.as_deref(),
+                value,
+                op: &rule.op,
+                threshold: rule.threshold,
+            },
+            breached,
+            now,
+        )?;
+    }
+    Ok(())
+}
+
+struct AlertCheck<'a> {
+    rule_id: &'a str,
+    token: &'a str,
+    metric: &'a str,
+    target: Option<&'a str>,
+    value: Option<f64>,
+    op: &'a str,
+    threshold: f64,
+}
+
+/// 同一规则 + 节点最多只有一条未恢复的告警事件：越界时开启，恢复时关闭。
+fn transition_alert(conn: &Connection, check: AlertCheck, breached: bool, now: f64) -> Result<(), AppError> {
+    let open: Option<i64> = conn
+        .query_row(
+            "SELECT id FROM alert_events WHERE rule_id = ? AND node_token = ? AND resolved_at IS NULL",
+            params![check.rule_id, check.token],
+            |row| row.get(0),
+        )
+        .ok();
+    match (breached, open) {
+        (true, None) => {
+            conn.execute(
+                "INSERT INTO alert_events (rule_id, node_token, metric, target, value, threshold, fired_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    check.rule_id,
+                    check.token,
+                    check.metric,
+                    check.target,
+                    check.value,
+                    check.threshold,
+                    now
+                ],
+            )?;
+            warn!(
+                "alert fired: node={} metric={} target={} value={:?} {} {}",
+                check.token,
+                check.metric,
+                check.target.unwrap_or("-"),
+                check.value,
+                check.op,
+                check.threshold
+            );
+        }
+        (false, Some(id)) => {
+            conn.execute(
+                "UPDATE alert_events SET resolved_at = ? WHERE id = ?",
+                params![now, id],
+            )?;
+            info!("alert resolved: node={} metric={}", check.token, check.metric);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Agent 上报的每个监视项都视为一条隐式规则，状态不是 ok 即告警。
+pub(crate) fn evaluate_watch_alerts(
+    conn: &Connection,
+    token: &str,
+    metrics: &Map<String, Value>,
+    now: f64,
+) -> Result<(), AppError> {
+    // 没有监视项时 Agent 不上报该字段，按空列表处理以便关闭遗留告警
+    let items = metrics
+        .get("watches")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut reported = Vec::with_capacity(items.len());
+    for item in items {
+        let id = match item.get("id").and_then(Value::as_str) {
+            Some(id) => id,
+            None => continue,
+        };
+        let up = item.get("status").and_then(Value::as_str) == Some("ok");
+        reported.push(format!("watch:{id}"));
+        transition_alert(
+            conn,
+            AlertCheck {
+                rule_id: &format!("watch:{id}"),
+                token,
+                metric: "watch.up",
+                target: item.get("name").and_then(Value::as_str),
+                value: Some(if up { 1.0 } else { 0.0 }),
+                op: "<",
+                threshold: 1.0,
+            },
+            !up,
+            now,
+        )?;
+    }
+    // 监视项被删除、改了标签不再作用于该节点或不再上报时，关闭其遗留告警
+    let mut stmt = conn.prepare(
+        "SELECT id, rule_id FROM alert_events
+        WHERE node_token = ? AND rule_id LIKE 'watch:%' AND resolved_at IS NULL",
+    )?;
+    let open: Vec<(i64, String)> = stmt
+        .query_map(params![token], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    for (id, rule_id) in open {
+        if reported.contains(&rule_id) {
+            continue;
+        }
+        conn.execute(
+            "UPDATE alert_events SET resolved_at = ? WHERE id = ?",
+            params![now, id],
+        )?;
+        info!("alert resolved: node={} rule={} no longer reported", token, rule_id);
+    }
+    Ok(())
+}
+
+fn load_alert_rules(conn: &Connection, token: Option<&str>) -> Result<Vec<AlertRule>, AppError> {
+    let sql = if token.is_some() {
+        "SELECT * FROM alert_rules WHERE node_token IS NULL OR node_token = ?1 ORDER BY created_at ASC"
+    } else {
+        "SELECT * FROM alert_rules ORDER BY created_at ASC"
+    };
+    let mut stmt = conn.prepare(sql)?;
+    let map_row = |row: &rusqlite::Row| {
+        Ok(AlertRule {
+            id: row.get("id")?,
+            node_token: row.get("node_token")?,
+            metric: row.get("metric")?,
+            target: row.get("target")?,
+            op: row.get("op")?,
+            threshold: row.get("threshold")?,
+            created_at: row.get("created_at")?,
+        })
+    };
+    let rows = match token {
+        Some(t) => stmt.query_map(params![t], map_row)?,
+        None => stmt.query_map([], map_row)?,
+    };
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+fn create_alert_rule(db_path: &Path, req: &CreateAlertRuleRequest) -> Result<String, AppError> {
+    let op = req.op.clone().unwrap_or_else(|| ">".to_string());
+    if op != ">" && op != "<" {
+        return Err(AppError::BadRequest("op must be '>' or '<'".into()));
+    }
+    if let Some((prefix, _)) = req.metric.split_once('.') {
+        let is_list = LIST_METRICS.iter().any(|(p, _, _)| *p == prefix);
+        if is_list && req.target.as_deref().unwrap_or("").is_empty() {
+            return Err(AppError::BadRequest("target required for list metrics".into()));
+        }
+    }
+    let conn = Connection::open(db_path)?;
+    let id = Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO alert_rules (id, node_token, metric, target, op, threshold, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, strftime('%s','now'))",
+        params![id, req.node_token, req.metric, req.target, op, req.threshold],
+    )?;
+    Ok(id)
+}
+
+fn delete_alert_rule(db_path: &Path, id: &str) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    let rows = conn.execute("DELETE FROM alert_rules WHERE id = ?", params![id])?;
+    if rows == 0 {
+        return Err(AppError::NotFound);
+    }
+    conn.execute(
+        "UPDATE alert_events SET resolved_at = strftime('%s','now') WHERE rule_id = ? AND resolved_at IS NULL",
+        params![id],
+    )?;
+    Ok(())
+}
+
+fn list_alert_events(db_path: &Path) -> Result<Vec<AlertEvent>, AppError> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT * FROM alert_events ORDER BY resolved_at IS NOT NULL, fired_at DESC LIMIT 200",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(AlertEvent {
+            id: row.get("id")?,
+            rule_id: row.get("rule_id")?,
+            node_token: row.get("node_token")?,
+            metric: row.get("metric")?,
+            target: row.get("target")?,
+            value: row.get("value")?,
+            threshold: row.get("threshold")?,
+            fired_at: row.get("fired_at")?,
+            resolved_at: row.get("resolved_at")?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
@@ -1,26 +1,168 @@
 use libc::statvfs;
+use regex::Regex;
 use reqwest::{blocking::Client, Url};
 use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
     env,
-    ffi::CStr,
+    ffi::{CStr, CString},
     fs::File,
-    io::{BufRead, BufReader},
-    net::UdpSocket,
+    hash::BuildHasher,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
     os::raw::c_char,
-    process,
+    os::unix::{fs::PermissionsExt, net::UnixStream, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
 
+const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 const DEFAULT_INTERVAL: u64 = 3;
 const DEFAULT_FLAG: &str = "🖥️";
+// 默认跳过的挂载类型为伪文件系统加上网络文件系统
+const PSEUDO_FS: &[&str] = &[
+    "autofs", "binfmt_misc", "bpf", "cgroup", "cgroup2", "configfs", "debugfs", "devpts",
+    "devtmpfs", "efivarfs", "fusectl", "fuse.lxcfs", "hugetlbfs", "mqueue", "nsfs", "overlay",
+    "proc", "pstore", "ramfs", "rpc_pipefs", "securityfs", "selinuxfs", "squashfs", "sysfs",
+    "tmpfs", "tracefs",
+];
+// 网络文件系统在服务端失联时 statvfs 会无限期阻塞
+const NETWORK_FS: &[&str] = &[
+    "nfs", "nfs4", "cifs", "smb3", "smbfs", "ceph", "glusterfs", "fuse.glusterfs", "fuse.sshfs",
+    "fuse.s3fs", "fuse.rclone", "9p", "afs", "fuse.davfs2", "lustre",
+];
+// 显式统计网络文件系统时单次 statvfs 的等待上限
+const STATVFS_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_NET_EXCLUDE: &[&str] = &[
+    "lo", "docker*", "veth*", "br-*", "virbr*", "vnet*", "cni*", "flannel*", "cali*", "kube-*",
+    "tunl*", "vxlan*", "lxcbr*", "lxdbr*",
+];
+const DEFAULT_DISK_EXCLUDE: &[&str] = &["loop*", "ram*", "zram*", "sr*", "fd*"];
+const SECTOR_SIZE: u64 = 512;
+const DEFAULT_TOP_N: usize = 5;
+const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+// Docker API 单次请求的读写超时，以及并发拉取容器详情的线程数
+const DOCKER_TIMEOUT: Duration = Duration::from_secs(2);
+const DOCKER_WORKERS: usize = 8;
+const DEFAULT_BUFFER_SIZE: usize = 600;
+// WebSocket 空闲时发送 Ping 的间隔，面板 45 秒收不到任何帧会断开
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RECONNECT_DELAY: u64 = 60;
+// 单个批量请求的样本数上限；每轮最多发送 BACKFILL_ROUNDS 批，避免恢复连接后长时间阻塞采集
+const BACKFILL_BATCH: usize = 200;
+const BACKFILL_ROUNDS: usize = 5;
+// 开启自动更新时检查面板上 Agent 版本的间隔
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_PLUGIN_TIMEOUT: u64 = 10;
+// 单个插件读取的输出上限与每次上报的自定义指标数上限
+const MAX_PLUGIN_OUTPUT: u64 = 1024 * 1024;
+const MAX_CUSTOM_METRICS: usize = 500;
+const DEFAULT_STATSD_ADDR: &str = "127.0.0.1:8125";
+// StatsD 同时跟踪的指标名上限，超出后新名称被丢弃
+const MAX_STATSD_METRICS: usize = 500;
+// 计数器连续这么多次上报没有新数据后不再跟踪，腾出名额
+const STATSD_COUNTER_IDLE_FLUSHES: u32 = 10;
+// 每个计时器每周期保留的样本数上限，超出后按蓄水池抽样替换，分位数为近似值
+const MAX_TIMER_SAMPLES: usize = 1000;
 
+#[derive(Clone)]
 struct Config {
     token: String,
     endpoint: String,
     interval: u64,
     flag: String,
+    skip_fs: Vec<String>,
+    net_include: Vec<String>,
+    net_exclude: Vec<String>,
+    disk_exclude: Vec<String>,
+    top_n: usize,
+    systemd_bus: Option<String>,
+    docker_socket: Option<String>,
+    buffer_size: usize,
+    /// gzip / zstd，None 表示不压缩
+    compression: Option<String>,
+    flush_interval: u64,
+    encoding: String,
+    /// http / ws
+    transport: String,
+    /// 面板下发配置中关闭的采集项
+    disabled: HashSet<String>,
+    /// 定期检查并安装面板提供的新版 Agent
+    auto_update: bool,
+    /// 仅执行一次更新后退出
+    self_update: bool,
+    /// 校验配置与面板连通性后退出
+    check: bool,
+    /// 只采集一轮
+    once: bool,
+    /// 将样本打印到标准输出而不发送
+    print: bool,
+    config_file: Option<PathBuf>,
+    /// 配置文件中的 [[plugins]]
+    plugins: Vec<PluginSpec>,
+    /// node_exporter textfile 风格的 *.prom 目录
+    textfile_dir: Option<String>,
+    /// StatsD UDP 监听地址，None 表示关闭
+    statsd_listen: Option<String>,
+}
+
+/// 按周期执行的指标脚本。
+#[derive(Clone, PartialEq)]
+struct PluginSpec {
+    name: String,
+    /// 字符串命令经 sh -c 执行，数组直接作为 argv
+    command: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// 各插件最近一次成功执行的结果，失败时清空，避免上报过期数据。
+type PluginResults = Arc<Mutex<HashMap<String, Vec<(String, f64)>>>>;
+
+impl Config {
+    fn enabled(&self, collector: &str) -> bool {
+        !self.disabled.contains(collector)
+    }
+
+    /// 离线缓冲的样本数上限；批量模式下至少要容纳一个上报周期内的样本。
+    /// 采集间隔可由面板修改，每次入队时重新计算。
+    fn backlog_capacity(&self) -> usize {
+        if self.flush_interval > 0 {
+            self.buffer_size.max(self.flush_interval.div_ceil(self.interval) as usize + 1)
+        } else {
+            self.buffer_size
+        }
+    }
+
+    /// 关闭 custom 采集时不运行插件。
+    fn active_plugins(&self) -> &[PluginSpec] {
+        if self.enabled("custom") {
+            &self.plugins
+        } else {
+            &[]
+        }
+    }
+}
+
+enum PushOutcome {
+    Accepted(Option<Value>),
+    /// 面板明确拒绝（如 token 无效），重试也不会成功
+    Rejected,
+    /// 接口不存在（HTTP 404/405），如旧版面板没有批量接口；单条上报时即 token 未登记
+    NotFound,
+    Unreachable,
+    /// 面板不支持所用的编码（HTTP 415）
+    Unsupported,
 }
 
 #[derive(Clone, Copy)]
@@ -35,6 +177,138 @@ struct CpuTimes {
     steal: u64,
 }
 
+#[derive(Default)]
+struct MemInfo {
+    total: u64,
+    free: u64,
+    available: u64,
+    buffers: u64,
+    cached: u64,
+    shared: u64,
+    dirty: u64,
+    swap_total: u64,
+    swap_free: u64,
+    hugepages_total: u64,
+    hugepages_free: u64,
+    hugepage_size: u64,
+}
+
+impl MemInfo {
+    fn used(&self) -> u64 {
+        self.total.saturating_sub(self.available)
+    }
+}
+
+struct FsUsage {
+    total: u64,
+    used: u64,
+    free: u64,
+    inodes_total: u64,
+    inodes_used: u64,
+}
+
+struct MountUsage {
+    mount_point: String,
+    device: String,
+    fs_type: String,
+    usage: FsUsage,
+}
+
+struct NetIface {
+    name: String,
+    rx_bytes: u64,
+    rx_packets: u64,
+    rx_errors: u64,
+    rx_drops: u64,
+    tx_bytes: u64,
+    tx_packets: u64,
+    tx_errors: u64,
+    tx_drops: u64,
+}
+
+#[derive(Clone)]
+struct DiskStat {
+    name: String,
+    reads: u64,
+    sectors_read: u64,
+    read_ms: u64,
+    writes: u64,
+    sectors_written: u64,
+    write_ms: u64,
+    io_ms: u64,
+}
+
+struct TempSensor {
+    sensor: String,
+    source: &'static str,
+    celsius: f64,
+    high: Option<f64>,
+    critical: Option<f64>,
+}
+
+struct ProcSample {
+    pid: u32,
+    name: String,
+    state: char,
+    threads: u64,
+    cpu_ticks: u64,
+    start_time: u64,
+    rss: u64,
+}
+
+struct Watch {
+    id: String,
+    kind: String,
+    name: String,
+    cmdline: Option<Regex>,
+    pidfile: Option<String>,
+}
+
+#[derive(Default)]
+struct WatchState {
+    /// 上一轮匹配到的 (pid, starttime)
+    pids: Vec<(u32, u64)>,
+    restarts: u64,
+    seen_up: bool,
+}
+
+/// ListUnits 返回的 (ssssssouso) 结构
+type UnitRow = (
+    String,
+    String,
+    String,
+    String,
+    String,
+    String,
+    OwnedObjectPath,
+    u32,
+    String,
+    OwnedObjectPath,
+);
+
+struct SocketEntry {
+    local: String,
+    port: u16,
+    remote_port: u16,
+    state: u8,
+    inode: u64,
+}
+
+#[derive(Default)]
+struct CgroupSample {
+    version: u8,
+    mem_usage: u64,
+    mem_limit: Option<u64>,
+    mem_inactive_file: u64,
+    cpu_quota: Option<f64>,
+    cpu_usage_usec: Option<u64>,
+    nr_periods: u64,
+    nr_throttled: u64,
+    throttled_usec: u64,
+    pids_current: Option<u64>,
+    pids_max: Option<u64>,
+}
+
 struct CpuInfo {
     model: String,
     cores: u64,
@@ -43,13 +317,25 @@ struct CpuInfo {
 }
 
 fn main() {
-    let cfg = match load_config() {
-        Ok(cfg) => cfg,
+    let args: Vec<String> = env::args().skip(1).collect();
+    let base_cfg = match load_config(&args, |name| env::var(name).ok()) {
+        Ok(Invocation::Run(cfg)) => *cfg,
+        Ok(Invocation::Help) => {
+            print_usage();
+            return;
+        }
+        Ok(Invocation::Version) => {
+            println!("imonitor-agent {AGENT_VERSION}");
+            return;
+        }
         Err(err) => {
             eprintln!("[agent] {err}");
             process::exit(1);
         }
     };
+    // 面板下发的配置叠加在本地配置之上，撤销后恢复本地值
+    let mut cfg = base_cfg.clone();
+    let mut config_version = String::new();
 
     let report_url = Url::parse(&format!(
         "{}/api/report",
@@ -59,6 +345,20 @@ fn main() {
         eprintln!("[agent] invalid endpoint URL: {e}");
         process::exit(1);
     });
+    let batch_url = report_url.join("report/batch").unwrap_or_else(|e| {
+        eprintln!("[agent] invalid endpoint URL: {e}");
+        process::exit(1);
+    });
+
+    let ws_url = (cfg.transport == "ws").then(|| {
+        let mut url = report_url.join("agent/ws").unwrap_or_else(|e| {
+            eprintln!("[agent] invalid endpoint URL: {e}");
+            process::exit(1);
+        });
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        let _ = url.set_scheme(scheme);
+        url
+    });
 
     let client = Client::builder()
         .timeout(Duration::from_secs(10))
@@ -67,52 +367,217 @@ fn main() {
             eprintln!("[agent] failed to build http client: {e}");
             process::exit(1);
         });
+    if cfg.check {
+        process::exit(if run_check(&cfg, &client) { 0 } else { 1 });
+    }
+    if cfg.self_update {
+        match update_agent(&client, &cfg.endpoint) {
+            Ok(Some(version)) => eprintln!("[agent] installed agent {version}, restart the service to apply"),
+            Ok(None) => eprintln!("[agent] agent is up to date ({AGENT_VERSION})"),
+            Err(err) => {
+                eprintln!("[agent] update failed: {err}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let mut uplink = Uplink {
+        client,
+        report_url,
+        batch_url,
+        compression: cfg.compression.clone(),
+        encoding: cfg.encoding.clone(),
+        token: cfg.token.clone(),
+        batch_supported: true,
+        ws_url,
+        socket: None,
+        ws_failures: 0,
+        reconnect_at: None,
+        last_frame: Instant::now(),
+        inbox: Vec::new(),
+    };
 
     let hostname = get_hostname();
     let ip_cache = detect_ip();
     let (os_short, os_full) = read_os_info();
     let cpu_info = read_cpu_info();
+    let container_runtime = detect_container_runtime();
+    let mut prev_cgroup: Option<(Instant, CgroupSample)> = None;
+    let mut backlog: VecDeque<Value> = VecDeque::new();
+    let mut last_flush: Option<Instant> = None;
 
     let mut prev_cpu = read_cpu_times();
-    let mut prev_net = read_net_bytes().unwrap_or((0, 0));
+    let mut prev_procs: (Instant, HashMap<u32, (u64, u64)>) = (Instant::now(), HashMap::new());
+    let mut watches: Vec<Watch> = Vec::new();
+    let mut watch_states: HashMap<String, WatchState> = HashMap::new();
+    let mut bus: Option<zbus::blocking::Connection> = None;
+    let mut prev_container_cpu: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut docker_failing = false;
+    let mut last_update_check: Option<Instant> = None;
+    let mut update_requested = false;
+    // 速率按两次读取之间实际经过的时间计算，采集耗时或间隔变化时也准确
+    let mut prev_disks = (Instant::now(), read_diskstats("/proc/diskstats", &cfg.disk_exclude));
+    let mut prev_ifaces = (Instant::now(), read_net_dev("/proc/net/dev", &cfg.net_include, &cfg.net_exclude));
+    let mut plugins = start_plugins(cfg.active_plugins());
+    let mut custom_dropped = 0;
+    let statsd = cfg.statsd_listen.as_deref().and_then(start_statsd);
+    // 单次运行先等待一个周期，速率与 CPU 占用才有意义
+    if cfg.once {
+        thread::sleep(Duration::from_secs(cfg.interval));
+    }
 
     loop {
         let start = Instant::now();
 
-        let cpu_usage = {
-            let current = read_cpu_times();
-            let usage = match (prev_cpu, current) {
-                (Some(prev), Some(curr)) => compute_cpu_usage(prev, curr),
-                _ => 0.0,
-            };
-            prev_cpu = current;
-            usage
+        // 插件列表或 custom 开关随面板配置变化时重启整组插件
+        if plugins.specs != cfg.active_plugins() {
+            plugins = start_plugins(cfg.active_plugins());
+        }
+
+        let current = read_cpu_times();
+        let (cpu_usage, cpu_breakdown, cpu_per_core) = match (&prev_cpu, &current) {
+            (Some((prev, prev_cores)), Some((curr, curr_cores))) => (
+                compute_cpu_usage(*prev, *curr),
+                Some(compute_cpu_breakdown(*prev, *curr)),
+                per_core_usage(prev_cores, curr_cores),
+            ),
+            _ => (0.0, None, Vec::new()),
         };
+        prev_cpu = current;
 
-        let (mem_total, mem_available) = read_meminfo().unwrap_or((0, 0));
-        let memory_percent = if mem_total > 0 {
-            let used = mem_total.saturating_sub(mem_available);
-            (used as f64 / mem_total as f64) * 100.0
+        let mem = read_meminfo("/proc/meminfo").unwrap_or_default();
+        let mut memory_percent = percent(mem.used(), mem.total);
+        let mut cpu_usage = cpu_usage;
+        // 容器内 /proc 多为宿主机数据，改用 cgroup 的配额与用量
+        let cgroup = if container_runtime.is_some() && cfg.enabled("cgroup") {
+            read_cgroup("/sys/fs/cgroup", "/proc/1/cgroup").map(|cg| (Instant::now(), cg))
         } else {
-            0.0
+            None
         };
+        let cgroup_json = cgroup.as_ref().map(|(at, cg)| {
+            let prev = prev_cgroup
+                .as_ref()
+                .map(|(prev_at, prev)| (prev, at.duration_since(*prev_at).as_secs_f64()));
+            let (json, cpu_percent) = cgroup_to_json(cg, prev, cpu_info.cores);
+            if let Some(limit) = cg.mem_limit.filter(|l| mem.total == 0 || *l < mem.total) {
+                memory_percent = percent(cg.mem_usage.saturating_sub(cg.mem_inactive_file), limit);
+            }
+            // 没有配额时 cgroup 用量与宿主机口径一致，保留 /proc/stat 的结果
+            if let Some(pct) = cpu_percent.filter(|_| cg.cpu_quota.is_some()) {
+                cpu_usage = pct;
+            }
+            json
+        });
+        prev_cgroup = cgroup;
+        let swap_used = mem.swap_total.saturating_sub(mem.swap_free);
 
-        let disk_percent = read_disk_percent().unwrap_or(0.0);
+        // 关闭 mounts 时仍需读取，用于计算 disk_percent
+        let mounts = read_mounts("/proc/self/mountinfo", &cfg.skip_fs);
+        let disk_percent = total_disk_percent(&mounts).or_else(read_disk_percent).unwrap_or(0.0);
+        let mounts = cfg.enabled("mounts").then_some(mounts);
+        let disks = read_diskstats("/proc/diskstats", &cfg.disk_exclude);
+        let disks_at = Instant::now();
+        let disk_secs = disks_at.duration_since(prev_disks.0).as_secs_f64();
+        let disk_io: Vec<Value> = disks
+            .iter()
+            .filter_map(|curr| {
+                let prev = prev_disks.1.iter().find(|p| p.name == curr.name)?;
+                Some(compute_disk_io(prev, curr, disk_secs))
+            })
+            .collect();
+        prev_disks = (disks_at, disks);
         let load_avg = read_loadavg().unwrap_or([0.0, 0.0, 0.0]);
+        let pressure = cfg
+            .enabled("pressure")
+            .then(|| read_pressure("/proc/pressure"))
+            .flatten();
+        let sockets = cfg.enabled("sockets").then(|| read_socket_summary("/proc"));
+        let conntrack = cfg
+            .enabled("conntrack")
+            .then(|| read_conntrack("/proc/sys/net/netfilter"))
+            .flatten();
+        let temperatures = if cfg.enabled("temperatures") {
+            read_temperatures("/sys")
+        } else {
+            Vec::new()
+        };
+        // 监视项与端口归属也依赖进程列表
+        let need_procs = cfg.enabled("processes") || cfg.enabled("listening") || !watches.is_empty();
+        let procs = if need_procs { read_processes("/proc") } else { Vec::new() };
+        let procs_at = Instant::now();
+        let procs_secs = procs_at.duration_since(prev_procs.0).as_secs_f64();
+        let processes = cfg
+            .enabled("processes")
+            .then(|| summarize_processes(&procs, &prev_procs.1, procs_secs, cfg.top_n));
+        prev_procs = (procs_at, procs.iter().map(|p| (p.pid, (p.start_time, p.cpu_ticks))).collect());
+        // 监视 systemd 单元时复用同一条 D-Bus 连接，未指定总线时使用 system
+        let watch_bus = cfg.systemd_bus.as_deref().unwrap_or("system");
+        let watch_report = check_watches(&watches, &mut watch_states, "/proc", &procs, &mut bus, watch_bus);
+        let listening = cfg.enabled("listening").then(|| read_listening("/proc", &procs));
+        let containers = cfg
+            .docker_socket
+            .as_deref()
+            .filter(|_| cfg.enabled("containers"))
+            .and_then(|sock| match read_containers(sock, &mut prev_container_cpu) {
+                // 连续失败（如未安装 Docker）只记录第一次，恢复时再记录一次
+                Ok(list) => {
+                    if docker_failing {
+                        eprintln!("[agent] docker: recovered");
+                        docker_failing = false;
+                    }
+                    Some(list)
+                }
+                Err(err) => {
+                    if !docker_failing {
+                        eprintln!("[agent] docker: {err}");
+                        docker_failing = true;
+                    }
+                    None
+                }
+            });
+        let systemd = cfg
+            .systemd_bus
+            .as_deref()
+            .filter(|_| cfg.enabled("systemd"))
+            .and_then(|kind| read_systemd_units(&mut bus, kind));
         let uptime = read_uptime().unwrap_or(0);
 
-        let net = read_net_bytes().unwrap_or((0, 0));
-        let delta_sent = net.0.saturating_sub(prev_net.0);
-        let delta_recv = net.1.saturating_sub(prev_net.1);
-        prev_net = net;
-        let sent_speed = bytes_per_sec_to_mb(delta_sent, cfg.interval);
-        let recv_speed = bytes_per_sec_to_mb(delta_recv, cfg.interval);
+        let ifaces = read_net_dev("/proc/net/dev", &cfg.net_include, &cfg.net_exclude);
+        let ifaces_at = Instant::now();
+        let net_secs = ifaces_at.duration_since(prev_ifaces.0).as_secs_f64();
+        let mut net = (0u64, 0u64);
+        let mut delta_sent = 0u64;
+        let mut delta_recv = 0u64;
+        let mut iface_list = Vec::with_capacity(ifaces.len());
+        for iface in &ifaces {
+            net.0 = net.0.saturating_add(iface.tx_bytes);
+            net.1 = net.1.saturating_add(iface.rx_bytes);
+            // 新出现的网卡没有上一轮数据，本轮速率记为 0
+            let (d_tx, d_rx) = match prev_ifaces.1.iter().find(|p| p.name == iface.name) {
+                Some(prev) => (
+                    iface.tx_bytes.saturating_sub(prev.tx_bytes),
+                    iface.rx_bytes.saturating_sub(prev.rx_bytes),
+                ),
+                None => (0, 0),
+            };
+            delta_sent = delta_sent.saturating_add(d_tx);
+            delta_recv = delta_recv.saturating_add(d_rx);
+            iface_list.push(iface_to_json(
+                iface,
+                bytes_per_sec_to_mb(d_tx, net_secs),
+                bytes_per_sec_to_mb(d_rx, net_secs),
+            ));
+        }
+        prev_ifaces = (ifaces_at, ifaces);
+        let sent_speed = bytes_per_sec_to_mb(delta_sent, net_secs);
+        let recv_speed = bytes_per_sec_to_mb(delta_recv, net_secs);
 
         let mut meta = Map::new();
         meta.insert("os".into(), json!(os_short.clone()));
         meta.insert("os_short".into(), json!(os_short.clone()));
         meta.insert("os_full".into(), json!(os_full.clone()));
         meta.insert("arch".into(), json!(env::consts::ARCH));
+        meta.insert("agent_version".into(), json!(AGENT_VERSION));
         meta.insert("cpu_model".into(), json!(cpu_info.model.clone()));
         meta.insert("cpu_cores".into(), json!(cpu_info.cores));
         meta.insert("hypervisor".into(), json!(cpu_info.hypervisor));
@@ -120,11 +585,44 @@ fn main() {
             meta.insert("hypervisor_vendor".into(), json!(v));
         }
         meta.insert("flag".into(), json!(cfg.flag.clone()));
+        meta.insert("container_runtime".into(), json!(container_runtime));
 
         let mut metrics = Map::new();
         metrics.insert("cpu".into(), json!(round2(cpu_usage)));
+        if let Some(breakdown) = cpu_breakdown {
+            metrics.insert("cpu_breakdown".into(), breakdown);
+        }
+        metrics.insert("cpu_per_core".into(), json!(cpu_per_core));
         metrics.insert("memory_percent".into(), json!(round2(memory_percent)));
+        metrics.insert("swap_percent".into(), json!(round2(percent(swap_used, mem.swap_total))));
+        metrics.insert(
+            "memory".into(),
+            json!({
+                "total": mem.total,
+                "used": mem.used(),
+                "available": mem.available,
+                "free": mem.free,
+                "cached": mem.cached,
+                "buffers": mem.buffers,
+                "shared": mem.shared,
+                "dirty": mem.dirty,
+                "swap_total": mem.swap_total,
+                "swap_used": swap_used,
+                "hugepages_total": mem.hugepages_total,
+                "hugepages_free": mem.hugepages_free,
+                "hugepage_size": mem.hugepage_size,
+            }),
+        );
         metrics.insert("disk_percent".into(), json!(round2(disk_percent)));
+        if let Some(mounts) = mounts {
+            metrics.insert(
+                "mounts".into(),
+                Value::Array(mounts.iter().map(mount_to_json).collect()),
+            );
+        }
+        if cfg.enabled("disk_io") {
+            metrics.insert("disk_io".into(), Value::Array(disk_io));
+        }
         metrics.insert("net_sent_speed".into(), json!(round3(sent_speed)));
         metrics.insert("net_recv_speed".into(), json!(round3(recv_speed)));
         metrics.insert(
@@ -135,6 +633,9 @@ fn main() {
             "total_recv".into(),
             json!(round3(bytes_to_gb(net.1))),
         );
+        metrics.insert("total_sent_bytes".into(), json!(net.0));
+        metrics.insert("total_recv_bytes".into(), json!(net.1));
+        metrics.insert("interfaces".into(), Value::Array(iface_list));
         metrics.insert(
             "load_avg".into(),
             json!(vec![
@@ -144,6 +645,66 @@ fn main() {
             ]),
         );
         metrics.insert("uptime".into(), json!(uptime));
+        // 4.20 以下内核或未开启 CONFIG_PSI 时不上报该字段
+        if let Some(pressure) = pressure {
+            metrics.insert("pressure".into(), pressure);
+        }
+        if let Some(processes) = processes {
+            metrics.insert("processes".into(), processes);
+        }
+        if let Some(sockets) = sockets {
+            metrics.insert("sockets".into(), sockets);
+        }
+        if let Some(listening) = listening {
+            metrics.insert("listening".into(), Value::Array(listening));
+        }
+        // 未加载 nf_conntrack 模块时不上报
+        if let Some(conntrack) = conntrack {
+            metrics.insert("conntrack".into(), conntrack);
+        }
+        if let Some(cgroup) = cgroup_json {
+            metrics.insert("cgroup".into(), cgroup);
+        }
+        if let Some(containers) = containers {
+            metrics.insert("containers".into(), Value::Array(containers));
+        }
+        if let Some(systemd) = systemd {
+            metrics.insert("systemd".into(), systemd);
+        }
+        if !watch_report.is_empty() {
+            metrics.insert("watches".into(), Value::Array(watch_report));
+        }
+        // 关闭 custom 时仍按周期取出，避免计数器无限累积
+        let statsd_values = statsd
+            .as_ref()
+            .map(|state| state.lock().unwrap().flush())
+            .unwrap_or_default();
+        if cfg.enabled("custom") {
+            let (custom, dropped) = collect_custom(&plugins.results, cfg.textfile_dir.as_deref(), statsd_values);
+            // 丢弃数量变化时记录一次
+            if dropped != custom_dropped {
+                if dropped > 0 {
+                    eprintln!("[agent] custom metrics over the limit of {MAX_CUSTOM_METRICS}, dropped {dropped}");
+                } else {
+                    eprintln!("[agent] custom metrics back under the limit");
+                }
+                custom_dropped = dropped;
+            }
+            if !custom.is_empty() {
+                metrics.insert("custom".into(), Value::Object(custom));
+            }
+        }
+        if !temperatures.is_empty() {
+            let max = temperatures
+                .iter()
+                .map(|t| t.celsius)
+                .fold(f64::MIN, f64::max);
+            metrics.insert("temperature_max".into(), json!(round2(max)));
+            metrics.insert(
+                "temperatures".into(),
+                Value::Array(temperatures.iter().map(temp_to_json).collect()),
+            );
+        }
 
         let payload = json!({
             "token": cfg.token,
@@ -151,251 +712,2988 @@ fn main() {
             "ip_address": ip_cache,
             "meta": Value::Object(meta),
             "metrics": Value::Object(metrics),
+            "config_version": config_version,
         });
 
-        match client.post(report_url.clone()).json(&payload).send() {
-            Ok(resp) => {
-                if !resp.status().is_success() {
-                    eprintln!(
-                        "[agent] server rejected payload: {} {}",
-                        resp.status(),
-                        resp.text().unwrap_or_default()
-                    );
+        let mut delivered = false;
+        let body = if cfg.print {
+            println!("{}", serde_json::to_string_pretty(&payload).unwrap_or_default());
+            None
+        } else if cfg.flush_interval > 0 {
+            buffer_sample(&mut backlog, payload, cfg.backlog_capacity());
+            let due = last_flush.is_none_or(|t| t.elapsed() >= Duration::from_secs(cfg.flush_interval));
+            if due {
+                last_flush = Some(Instant::now());
+                uplink.flush_backlog(&mut backlog)
+            } else {
+                None
+            }
+        } else {
+            match uplink.push_report(&payload) {
+                PushOutcome::Accepted(body) => {
+                    delivered = true;
+                    uplink.flush_backlog(&mut backlog);
+                    body
+                }
+                PushOutcome::Rejected | PushOutcome::NotFound | PushOutcome::Unsupported => None,
+                PushOutcome::Unreachable => {
+                    buffer_sample(&mut backlog, payload, cfg.backlog_capacity());
+                    None
                 }
             }
-            Err(err) => eprintln!("[agent] failed to push metrics: {err}"),
+        };
+        // 旧版面板的响应里没有 watches，保持现有列表
+        if let Some(list) = body.as_ref().and_then(|b| b.get("watches")).and_then(Value::as_array) {
+            watches = parse_watches(list);
+            watch_states.retain(|id, _| watches.iter().any(|w| &w.id == id));
         }
-
-        let elapsed = start.elapsed();
-        if elapsed < Duration::from_secs(cfg.interval) {
-            thread::sleep(Duration::from_secs(cfg.interval) - elapsed);
+        if let Some(remote) = body.as_ref().and_then(|b| b.get("config")) {
+            update_remote_config(&base_cfg, &mut cfg, &mut config_version, remote);
         }
-    }
-}
 
-fn load_config() -> Result<Config, String> {
-    let mut token = env::var("IMONITOR_TOKEN").ok();
-    let mut endpoint = env::var("IMONITOR_ENDPOINT").ok();
-    let mut interval = env::var("IMONITOR_INTERVAL")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok());
-    let mut flag = env::var("IMONITOR_FLAG").ok();
+        if cfg.once {
+            process::exit(if cfg.print || delivered { 0 } else { 1 });
+        }
 
-    let mut args = env::args().skip(1).peekable();
-    while let Some(arg) = args.next() {
-        match arg.as_str() {
-            "--token" => {
-                if let Some(val) = args.next() {
-                    token = Some(val);
-                }
-            }
-            "--endpoint" => {
-                if let Some(val) = args.next() {
-                    endpoint = Some(val);
-                }
-            }
-            "--interval" => {
-                if let Some(val) = args.next() {
-                    interval = val.parse::<u64>().ok();
-                }
-            }
-            "--flag" => {
-                if let Some(val) = args.next() {
-                    flag = Some(val);
+        let update_due = cfg.auto_update
+            && !cfg.print
+            && last_update_check.is_none_or(|t| t.elapsed() >= UPDATE_CHECK_INTERVAL);
+        if update_due || update_requested {
+            last_update_check = Some(Instant::now());
+            update_requested = false;
+            match update_agent(&uplink.client, &cfg.endpoint) {
+                Ok(Some(version)) => {
+                    eprintln!("[agent] installed agent {version}, restarting");
+                    uplink.flush_backlog(&mut backlog);
+                    restart_agent();
                 }
+                Ok(None) => {}
+                Err(err) => eprintln!("[agent] update failed: {err}"),
             }
-            _ => {
-                if let Some(val) = arg.strip_prefix("--token=") {
-                    token = Some(val.to_string());
-                } else if let Some(val) = arg.strip_prefix("--endpoint=") {
-                    endpoint = Some(val.to_string());
-                } else if let Some(val) = arg.strip_prefix("--interval=") {
-                    interval = val.parse::<u64>().ok();
-                } else if let Some(val) = arg.strip_prefix("--flag=") {
-                    flag = Some(val.to_string());
-                }
+        }
+
+        let remaining = Duration::from_secs(cfg.interval).saturating_sub(start.elapsed());
+        for message in uplink.wait(remaining) {
+            // 面板发起的更新在下一轮上报后执行，避免打断当前周期
+            if message.get("type").and_then(Value::as_str) == Some("update") {
+                update_requested = true;
+                continue;
             }
+            handle_panel_message(&message, &base_cfg, &mut cfg, &mut config_version);
         }
     }
-
-    let token = token.ok_or_else(|| "missing --token".to_string())?;
-    let endpoint = endpoint.ok_or_else(|| "missing --endpoint".to_string())?;
-    let interval = interval.unwrap_or(DEFAULT_INTERVAL).max(1);
-    let flag = flag.unwrap_or_else(|| DEFAULT_FLAG.to_string());
-
-    Ok(Config {
-        token,
-        endpoint,
-        interval,
-        flag,
-    })
 }
 
-fn detect_ip() -> String {
-    if let Ok(sock) = UdpSocket::bind("0.0.0.0:0") {
-        if sock.connect("8.8.8.8:80").is_ok() {
-            if let Ok(addr) = sock.local_addr() {
-                return addr.ip().to_string();
-            }
+/// --check：打印生效的配置，确认面板可达且令牌已登记。
+fn run_check(cfg: &Config, client: &Client) -> bool {
+    match &cfg.config_file {
+        Some(path) => println!("config file: {}", path.display()),
+        None => println!("config file: none"),
+    }
+    println!("endpoint: {}", cfg.endpoint);
+    println!(
+        "interval: {}s, transport: {}, encoding: {}, compression: {}",
+        cfg.interval,
+        cfg.transport,
+        cfg.encoding,
+        cfg.compression.as_deref().unwrap_or("none")
+    );
+    // 令牌放在 Authorization 头中，不出现在 URL 与面板访问日志里
+    let url = format!("{}/api/agent/config", cfg.endpoint.trim_end_matches('/'));
+    let start = Instant::now();
+    match client.get(url).bearer_auth(&cfg.token).send() {
+        Ok(resp) if resp.status().is_success() => {
+            println!("panel: reachable in {} ms, token registered", start.elapsed().as_millis());
+            true
+        }
+        Ok(resp) if resp.status() == reqwest::StatusCode::NOT_FOUND => {
+            println!("panel: reachable, but the token is not registered");
+            false
+        }
+        Ok(resp) => {
+            println!("panel: unexpected response {}", resp.status());
+            false
+        }
+        Err(err) => {
+            println!("panel: unreachable: {err}");
+            false
         }
     }
-    String::new()
 }
 
-fn get_hostname() -> String {
-    unsafe {
-        let mut buf = [0u8; 256];
-        if libc::gethostname(buf.as_mut_ptr() as *mut c_char, buf.len()) == 0 {
-            if let Ok(cstr) = CStr::from_ptr(buf.as_ptr() as *const c_char).to_str() {
-                return cstr.trim_end_matches(char::from(0)).to_string();
-            }
-        }
+/// 下载面板提供的 Agent，校验 SHA-256 并确认能在本机运行后原子替换当前可执行文件。
+/// 与当前文件一致时返回 None。
+fn update_agent(client: &Client, endpoint: &str) -> Result<Option<String>, String> {
+    let base = endpoint.trim_end_matches('/');
+    let release: Value = client
+        .get(format!("{base}/api/agent/release"))
+        .query(&[("arch", release_arch())])
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.json())
+        .map_err(|e| format!("failed to fetch release info: {e}"))?;
+    let version = release.get("version").and_then(Value::as_str).unwrap_or("unknown");
+    let expected = release
+        .get("sha256")
+        .and_then(Value::as_str)
+        .ok_or("release info without sha256")?;
+
+    let exe = current_exe()?;
+    let current = std::fs::read(&exe).map_err(|e| format!("failed to read {}: {e}", exe.display()))?;
+    if sha256_hex(&current).eq_ignore_ascii_case(expected) {
+        return Ok(None);
     }
-    "unknown".to_string()
+
+    let path = release_path(&release)?;
+    let binary = client
+        .get(format!("{base}{path}"))
+        .timeout(DOWNLOAD_TIMEOUT)
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.bytes())
+        .map_err(|e| format!("failed to download agent: {e}"))?;
+    let actual = sha256_hex(&binary);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(format!("checksum mismatch: expected {expected}, got {actual}"));
+    }
+
+    // 临时文件与目标同目录，rename 才是原子的
+    let name = exe.file_name().and_then(|n| n.to_str()).unwrap_or("agent");
+    let staged = exe.with_file_name(format!(".{name}.update"));
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&staged)?;
+        file.write_all(&binary)?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        file.sync_all()
+    };
+    if let Err(err) = write() {
+        let _ = std::fs::remove_file(&staged);
+        return Err(format!("failed to write {}: {err}", staged.display()));
+    }
+    let runs = Command::new(&staged)
+        .arg("--version")
+        .output()
+        .is_ok_and(|out| out.status.success() && out.stdout.starts_with(b"imonitor-agent"));
+    if !runs {
+        let _ = std::fs::remove_file(&staged);
+        return Err("downloaded agent does not run on this host".to_string());
+    }
+    std::fs::rename(&staged, &exe).map_err(|e| {
+        let _ = std::fs::remove_file(&staged);
+        format!("failed to replace {}: {e}", exe.display())
+    })?;
+    Ok(Some(version.to_string()))
 }
 
-fn read_cpu_times() -> Option<CpuTimes> {
-    let file = File::open("/proc/stat").ok()?;
-    let mut reader = BufReader::new(file);
-    let mut line = String::new();
-    reader.read_line(&mut line).ok()?;
-    let mut parts = line.split_whitespace();
-    if parts.next()? != "cpu" {
-        return None;
+/// 下载路径由面板给出，只接受面板 /agent/ 下的文件，避免被引导到其他地址；旧版面板不返回时使用 /agent.bin。
+fn release_path(release: &Value) -> Result<&str, String> {
+    match release.get("url").and_then(Value::as_str) {
+        None => Ok("/agent.bin"),
+        Some(path) if path.starts_with("/agent/") && !path.split('/').any(|seg| seg == "..") => Ok(path),
+        Some(path) => Err(format!("refusing to download from unexpected path {path:?}")),
     }
-    let nums: Vec<u64> = parts.filter_map(|p| p.parse::<u64>().ok()).collect();
-    if nums.len() < 8 {
-        return None;
+}
+
+/// 面板分发文件使用的架构名，32 位 ARM 统一按 armv7 硬浮点构建。
+fn release_arch() -> &'static str {
+    match env::consts::ARCH {
+        "arm" => "armv7",
+        other => other,
     }
-    Some(CpuTimes {
-        user: nums[0],
-        nice: nums[1],
-        system: nums[2],
-        idle: nums[3],
-        iowait: nums.get(4).copied().unwrap_or(0),
-        irq: nums.get(5).copied().unwrap_or(0),
-        softirq: nums.get(6).copied().unwrap_or(0),
-        steal: nums.get(7).copied().unwrap_or(0),
+}
+
+fn current_exe() -> Result<PathBuf, String> {
+    let exe = env::current_exe().map_err(|e| format!("failed to locate executable: {e}"))?;
+    // 文件被替换后 /proc/self/exe 会带上 " (deleted)" 后缀
+    Ok(match exe.to_str().and_then(|s| s.strip_suffix(" (deleted)")) {
+        Some(path) => PathBuf::from(path),
+        None => exe,
     })
 }
 
-fn compute_cpu_usage(prev: CpuTimes, curr: CpuTimes) -> f64 {
-    let prev_idle = prev.idle + prev.iowait;
-    let curr_idle = curr.idle + curr.iowait;
-    let prev_total = prev_idle + prev.user + prev.nice + prev.system + prev.irq + prev.softirq + prev.steal;
-    let curr_total = curr_idle + curr.user + curr.nice + curr.system + curr.irq + curr.softirq + curr.steal;
-    let totald = curr_total.saturating_sub(prev_total) as f64;
-    let idled = curr_idle.saturating_sub(prev_idle) as f64;
-    if totald <= 0.0 {
-        0.0
-    } else {
-        (1.0 - idled / totald) * 100.0
+/// 以原参数重新执行替换后的可执行文件，进程号不变，systemd 无感知。
+fn restart_agent() {
+    let exe = match current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            eprintln!("[agent] {err}");
+            return;
+        }
+    };
+    let err = Command::new(&exe).args(env::args_os().skip(1)).exec();
+    eprintln!("[agent] failed to exec {}: {err}", exe.display());
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// 正在运行的一组插件线程；被替换或丢弃时通知线程在当前这次执行结束后退出。
+struct PluginSet {
+    specs: Vec<PluginSpec>,
+    results: PluginResults,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for PluginSet {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 每个插件一个线程按各自周期执行，主循环只读取最近的结果。
+fn start_plugins(specs: &[PluginSpec]) -> PluginSet {
+    let results: PluginResults = Arc::new(Mutex::new(HashMap::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    for spec in specs.iter().cloned() {
+        let results = Arc::clone(&results);
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            // 连续失败只记录第一次，恢复时再记录一次
+            let mut failing = false;
+            while !stop.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                match run_plugin(&spec) {
+                    Ok(values) => {
+                        if failing {
+                            eprintln!("[agent] plugin {} recovered", spec.name);
+                            failing = false;
+                        }
+                        results.lock().unwrap().insert(spec.name.clone(), values);
+                    }
+                    Err(err) => {
+                        if !failing {
+                            eprintln!("[agent] plugin {}: {err}", spec.name);
+                            failing = true;
+                        }
+                        results.lock().unwrap().remove(&spec.name);
+                    }
+                }
+                // 分段休眠，配置变化后旧线程能及时退出
+                while !stop.load(Ordering::Relaxed) {
+                    let remaining = spec.interval.saturating_sub(started.elapsed());
+                    if remaining.is_zero() {
+                        break;
+                    }
+                    thread::sleep(remaining.min(Duration::from_secs(1)));
+                }
+            }
+        });
+    }
+    PluginSet {
+        specs: specs.to_vec(),
+        results,
+        stop,
     }
 }
 
-fn read_meminfo() -> Option<(u64, u64)> {
-    let file = File::open("/proc/meminfo").ok()?;
-    let reader = BufReader::new(file);
-    let mut total = 0;
-    let mut available = 0;
-    for line in reader.lines().flatten() {
-        if line.starts_with("MemTotal:") {
-            total = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
-        } else if line.starts_with("MemAvailable:") {
-            available = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+/// 执行插件并解析标准输出；超时后结束整个进程组，避免脚本派生的子进程残留。
+fn run_plugin(spec: &PluginSpec) -> Result<Vec<(String, f64)>, String> {
+    let mut child = Command::new(&spec.command[0])
+        .args(&spec.command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("failed to start {}: {e}", spec.command[0]))?;
+    let stdout = child.stdout.take().ok_or("stdout unavailable")?;
+    // 另起线程读取，输出较多时子进程不会因管道写满而阻塞；结果经通道返回，等待同样受超时约束
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.take(MAX_PLUGIN_OUTPUT).read_to_string(&mut output);
+        let _ = tx.send(output);
+    });
+    let pgid = child.id() as i32;
+    let kill_group = || unsafe {
+        libc::kill(-pgid, libc::SIGKILL);
+    };
+    let deadline = Instant::now() + spec.timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                kill_group();
+                let _ = child.wait();
+                // 读取线程在管道关闭后自行结束，不再等待
+                return Err(format!("timed out after {}s", spec.timeout.as_secs()));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(err) => {
+                kill_group();
+                let _ = child.wait();
+                return Err(err.to_string());
+            }
         }
-        if total > 0 && available > 0 {
-            break;
+    };
+    // 脚本退出后仍在后台运行的子进程会一直占用 stdout，连同进程组一起结束
+    kill_group();
+    if !status.success() {
+        return Err(format!("exited with {status}"));
+    }
+    let output = rx
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .map_err(|_| format!("output not closed within {}s", spec.timeout.as_secs()))?;
+    Ok(parse_plugin_output(&output))
+}
+
+/// 插件输出为 JSON 对象（嵌套键以 `.` 连接）或每行一个 `key value` / `key=value` / `key: value`。
+fn parse_plugin_output(output: &str) -> Vec<(String, f64)> {
+    let mut values = Vec::new();
+    let trimmed = output.trim();
+    if trimmed.starts_with('{') {
+        if let Ok(value) = serde_json::from_str::<Value>(trimmed) {
+            flatten_json("", &value, &mut values);
         }
+        return values;
     }
-    if total == 0 {
-        None
-    } else {
-        // values are in kB
-        Some((total * 1024, available * 1024))
+    for line in trimmed.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(|c: char| c == '=' || c == ':' || c.is_whitespace()) else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim().trim_start_matches(['=', ':']).trim());
+        if let Some(value) = value.parse::<f64>().ok().filter(|v| v.is_finite()) {
+            if !key.is_empty() {
+                values.push((key.to_string(), value));
+            }
+        }
     }
+    values
 }
 
-fn read_disk_percent() -> Option<f64> {
-    unsafe {
-        let mut vfs: statvfs = std::mem::zeroed();
-        if statvfs(b"/\0".as_ptr() as *const c_char, &mut vfs) != 0 {
-            return None;
+fn flatten_json(prefix: &str, value: &Value, out: &mut Vec<(String, f64)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                let name = if prefix.is_empty() { key.clone() } else { format!("{prefix}.{key}") };
+                flatten_json(&name, child, out);
+            }
         }
-        let blocks = vfs.f_blocks as f64;
-        if blocks <= 0.0 {
-            return None;
+        Value::Number(n) if !prefix.is_empty() => {
+            if let Some(v) = n.as_f64() {
+                out.push((prefix.to_string(), v));
+            }
         }
-        let free = vfs.f_bavail as f64;
-        let used = blocks - free;
-        Some((used / blocks) * 100.0)
+        Value::Bool(b) if !prefix.is_empty() => out.push((prefix.to_string(), if *b { 1.0 } else { 0.0 })),
+        _ => {}
     }
 }
 
-fn read_net_bytes() -> Option<(u64, u64)> {
-    let file = File::open("/proc/net/dev").ok()?;
-    let reader = BufReader::new(file);
-    let mut sent = 0u64;
-    let mut recv = 0u64;
-    for line in reader.lines().flatten().skip(2) {
-        if let Some((iface, data)) = line.split_once(':') {
-            let iface = iface.trim();
-            if iface.is_empty() {
+/// 读取目录下的 *.prom 文件（Prometheus 文本格式），标签保留在指标名中，如 `jobs{queue="mail"}`。
+fn read_textfiles(dir: &str) -> Vec<(String, f64)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "prom"))
+        .collect();
+    paths.sort();
+    let mut values = Vec::new();
+    for path in paths {
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
-            let fields: Vec<&str> = data.split_whitespace().collect();
-            if fields.len() < 9 {
-                continue;
+            // 标签值里可能有空格，名称取到右花括号为止；行尾可选的时间戳忽略
+            let (name, rest) = match line.find('{') {
+                Some(open) => match line[open..].find('}') {
+                    Some(close) => line.split_at(open + close + 1),
+                    None => continue,
+                },
+                None => line.split_once(char::is_whitespace).unwrap_or((line, "")),
+            };
+            let value = rest.split_whitespace().next().and_then(|v| v.parse::<f64>().ok());
+            if let Some(value) = value.filter(|v| v.is_finite()) {
+                values.push((name.trim().to_string(), value));
             }
-            let r: u64 = fields[0].parse().unwrap_or(0);
-            let t: u64 = fields[8].parse().unwrap_or(0);
-            recv = recv.saturating_add(r);
-            sent = sent.saturating_add(t);
         }
     }
-    Some((sent, recv))
+    values
 }
 
-fn read_loadavg() -> Option<[f64; 3]> {
-    let mut line = String::new();
-    let mut file = File::open("/proc/loadavg").ok()?;
-    use std::io::Read;
-    file.read_to_string(&mut line).ok()?;
-    let parts: Vec<&str> = line.split_whitespace().collect();
-    if parts.len() < 3 {
-        return None;
+/// 合并插件、textfile 与 StatsD 的指标，插件指标以插件名为前缀；同时返回超出上限被丢弃的个数。
+/// 超出 MAX_CUSTOM_METRICS 时按来源优先级保留：插件、textfile、StatsD，防止输出失控撑大上报体积。
+fn collect_custom(
+    plugins: &PluginResults,
+    textfile_dir: Option<&str>,
+    statsd: Vec<(String, f64)>,
+) -> (Map<String, Value>, usize) {
+    let mut custom = Map::new();
+    let mut dropped = 0;
+    let mut add = |name: String, value: f64| {
+        if custom.len() < MAX_CUSTOM_METRICS || custom.contains_key(&name) {
+            custom.insert(name, json!(value));
+        } else {
+            dropped += 1;
+        }
+    };
+    let mut plugin_values: Vec<(String, f64)> = plugins
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(plugin, values)| values.iter().map(move |(key, value)| (format!("{plugin}.{key}"), *value)))
+        .collect();
+    plugin_values.sort_by(|a, b| a.0.cmp(&b.0));
+    let textfile = textfile_dir.map(read_textfiles).unwrap_or_default();
+    for (name, value) in plugin_values.into_iter().chain(textfile).chain(statsd) {
+        add(name, value);
     }
-    let one = parts[0].parse::<f64>().ok()?;
-    let five = parts[1].parse::<f64>().ok()?;
-    let fifteen = parts[2].parse::<f64>().ok()?;
-    Some([one, five, fifteen])
+    (custom, dropped)
 }
 
-fn read_uptime() -> Option<u64> {
-    let mut line = String::new();
-    let mut file = File::open("/proc/uptime").ok()?;
-    use std::io::Read;
-    file.read_to_string(&mut line).ok()?;
-    let first = line.split_whitespace().next()?;
-    first.parse::<f64>().ok().map(|v| v as u64)
+/// StatsD 聚合状态，每次上报时取出：计数器与计时器按周期清零，gauge 保留最后的值。
+#[derive(Default)]
+struct StatsdState {
+    /// 本周期的累计值与连续无数据的上报次数
+    counters: HashMap<String, (f64, u32)>,
+    gauges: HashMap<String, f64>,
+    timers: HashMap<String, TimerStats>,
+    sets: HashMap<String, HashSet<String>>,
+    last_flush: Option<Instant>,
+    /// 蓄水池抽样用的随机源
+    random: RandomState,
 }
 
-fn read_os_info() -> (String, String) {
-    let mut pretty = String::new();
-    let mut name = String::new();
-    let mut version = String::new();
-    if let Ok(file) = File::open("/etc/os-release") {
-        for line in BufReader::new(file).lines().flatten() {
-            if line.starts_with("PRETTY_NAME=") {
-                pretty = line.trim_start_matches("PRETTY_NAME=").trim_matches('"').to_string();
-            } else if line.starts_with("NAME=") && name.is_empty() {
-                name = line.trim_start_matches("NAME=").trim_matches('"').to_string();
-            } else if line.starts_with("VERSION=") && version.is_empty() {
-                version = line.trim_start_matches("VERSION=").trim_matches('"').to_string();
+/// 一个周期内的计时器统计；均值与极值按全部取值计算，分位数来自抽样。
+#[derive(Default)]
+struct TimerStats {
+    /// 按采样率折算后的次数
+    count: f64,
+    seen: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    samples: Vec<f64>,
+}
+
+impl StatsdState {
+    fn tracked(&self) -> usize {
+        self.counters.len() + self.gauges.len() + self.timers.len() + self.sets.len()
+    }
+
+    /// 解析一行 `name:value|type[|@rate][|#tags]`，支持 c、g、ms、h、d、s；DogStatsD 标签忽略。
+    fn record(&mut self, line: &str) {
+        let Some((name, rest)) = line.trim().split_once(':') else {
+            return;
+        };
+        let name = name.trim();
+        let mut fields = rest.split('|');
+        let (Some(raw), Some(kind)) = (fields.next(), fields.next()) else {
+            return;
+        };
+        let rate = fields
+            .find_map(|f| f.strip_prefix('@'))
+            .and_then(|r| r.parse::<f64>().ok())
+            .filter(|r| *r > 0.0 && *r <= 1.0)
+            .unwrap_or(1.0);
+        let known = self.counters.contains_key(name)
+            || self.gauges.contains_key(name)
+            || self.timers.contains_key(name)
+            || self.sets.contains_key(name);
+        if name.is_empty() || (!known && self.tracked() >= MAX_STATSD_METRICS) {
+            return;
+        }
+        if kind == "s" {
+            self.sets.entry(name.to_string()).or_default().insert(raw.to_string());
+            return;
+        }
+        let Some(value) = raw.parse::<f64>().ok().filter(|v| v.is_finite()) else {
+            return;
+        };
+        match kind {
+            "c" => {
+                let counter = self.counters.entry(name.to_string()).or_default();
+                counter.0 += value / rate;
+                counter.1 = 0;
+            }
+            // 带符号的 gauge 为增量
+            "g" if raw.starts_with(['+', '-']) => *self.gauges.entry(name.to_string()).or_default() += value,
+            "g" => {
+                self.gauges.insert(name.to_string(), value);
+            }
+            "ms" | "h" | "d" => {
+                let timer = self.timers.entry(name.to_string()).or_default();
+                timer.count += 1.0 / rate;
+                if timer.seen == 0 || value < timer.min {
+                    timer.min = value;
+                }
+                if timer.seen == 0 || value > timer.max {
+                    timer.max = value;
+                }
+                timer.seen += 1;
+                timer.sum += value;
+                if timer.samples.len() < MAX_TIMER_SAMPLES {
+                    timer.samples.push(value);
+                } else {
+                    let slot = (self.random.hash_one(timer.seen) % timer.seen) as usize;
+                    if let Some(sample) = timer.samples.get_mut(slot) {
+                        *sample = value;
+                    }
+                }
             }
+            _ => {}
         }
     }
-    let os_short = if !pretty.is_empty() {
+
+    fn flush(&mut self) -> Vec<(String, f64)> {
+        let now = Instant::now();
+        let elapsed = self
+            .last_flush
+            .replace(now)
+            .map_or(1.0, |t| now.duration_since(t).as_secs_f64())
+            .max(0.001);
+        let mut out = Vec::new();
+        // 计数器名称保留，无新数据时上报 0，连续空闲过久后移除
+        self.counters.retain(|name, (count, idle)| {
+            out.push((format!("{name}.count"), round3(*count)));
+            out.push((format!("{name}.rate"), round3(*count / elapsed)));
+            if *count == 0.0 {
+                *idle += 1;
+            }
+            *count = 0.0;
+            *idle < STATSD_COUNTER_IDLE_FLUSHES
+        });
+        for (name, value) in &self.gauges {
+            out.push((name.clone(), *value));
+        }
+        for (name, mut timer) in self.timers.drain() {
+            let values = &mut timer.samples;
+            values.sort_by(f64::total_cmp);
+            let pct = |p: f64| values[((p * values.len() as f64).ceil() as usize).clamp(1, values.len()) - 1];
+            out.push((format!("{name}.count"), round3(timer.count)));
+            out.push((format!("{name}.mean"), round3(timer.sum / timer.seen as f64)));
+            out.push((format!("{name}.min"), timer.min));
+            out.push((format!("{name}.max"), timer.max));
+            out.push((format!("{name}.p50"), pct(0.5)));
+            out.push((format!("{name}.p95"), pct(0.95)));
+            out.push((format!("{name}.p99"), pct(0.99)));
+        }
+        for (name, members) in self.sets.drain() {
+            out.push((format!("{name}.count"), members.len() as f64));
+        }
+        out
+    }
+}
+
+/// 在后台线程接收 StatsD 数据包；绑定失败时记录日志并跳过。
+fn start_statsd(addr: &str) -> Option<Arc<Mutex<StatsdState>>> {
+    let socket = match UdpSocket::bind(addr) {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("[agent] failed to bind statsd listener on {addr}: {err}");
+            return None;
+        }
+    };
+    eprintln!("[agent] statsd listening on {addr}");
+    let state = Arc::new(Mutex::new(StatsdState {
+        last_flush: Some(Instant::now()),
+        ..Default::default()
+    }));
+    let shared = Arc::clone(&state);
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 65535];
+        // 持续出错时只记录第一次并放慢重试，避免空转占满 CPU
+        let mut failing = false;
+        loop {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if failing {
+                        eprintln!("[agent] statsd: recovered");
+                        failing = false;
+                    }
+                    len
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    if !failing {
+                        eprintln!("[agent] statsd: receive failed: {err}");
+                        failing = true;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+            let packet = String::from_utf8_lossy(&buf[..len]);
+            let mut state = shared.lock().unwrap();
+            for line in packet.lines() {
+                state.record(line);
+            }
+        }
+    });
+    Some(state)
+}
+
+/// 记下采集时间后放入环形缓冲区，满了丢弃最旧的样本。
+fn buffer_sample(backlog: &mut VecDeque<Value>, mut payload: Value, capacity: usize) {
+    if capacity == 0 {
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    payload["timestamp"] = json!(now);
+    // 上限随配置变小时一并丢弃多出的旧样本
+    while backlog.len() >= capacity {
+        backlog.pop_front();
+    }
+    backlog.push_back(payload);
+}
+
+/// 与面板通信的上报通道。
+struct Uplink {
+    client: Client,
+    report_url: Url,
+    batch_url: Url,
+    compression: Option<String>,
+    /// json / cbor / msgpack；面板不支持二进制编码时退回 json，之后不再尝试
+    encoding: String,
+    /// 建立 WebSocket 时放在 Authorization 头中，避免出现在 URL 与访问日志里
+    token: String,
+    /// 批量接口返回 404/405 后置为 false，之后积压的样本逐条补发
+    batch_supported: bool,
+    /// 为 Some 时通过 WebSocket 推送样本，连接时附加当前编码
+    ws_url: Option<Url>,
+    socket: Option<AgentSocket>,
+    /// 连续连接失败次数与下次重连时间，按指数退避
+    ws_failures: u32,
+    reconnect_at: Option<Instant>,
+    last_frame: Instant,
+    /// 面板主动下发、尚未处理的消息
+    inbox: Vec<Value>,
+}
+
+type AgentSocket = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+impl Uplink {
+    fn push_report(&mut self, payload: &Value) -> PushOutcome {
+        if self.ws_url.is_some() {
+            if let Some(outcome) = self.push_socket(payload) {
+                return outcome;
+            }
+        }
+        self.post_report(payload)
+    }
+
+    fn post_report(&mut self, payload: &Value) -> PushOutcome {
+        let url = self.report_url.clone();
+        self.send(url, payload, None)
+    }
+
+    /// 通过 WebSocket 发送样本并等待 ack；连接不可用时返回 None，由调用方改走 HTTP。
+    fn push_socket(&mut self, payload: &Value) -> Option<PushOutcome> {
+        if self.socket.is_none() {
+            self.connect_socket();
+        }
+        let socket = self.socket.as_mut()?;
+        let message = if self.encoding == "json" {
+            tungstenite::Message::Text(payload.to_string())
+        } else {
+            match encode_value(&self.encoding, payload) {
+                Ok(raw) => tungstenite::Message::Binary(raw),
+                Err(err) => {
+                    eprintln!("[agent] failed to encode payload: {err}");
+                    return Some(PushOutcome::Rejected);
+                }
+            }
+        };
+        if let Err(err) = socket.send(message) {
+            eprintln!("[agent] websocket send failed: {err}");
+            self.drop_socket();
+            return None;
+        }
+        self.last_frame = Instant::now();
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        loop {
+            match self.read_socket(deadline) {
+                Ok(Some(reply)) => match reply.get("type").and_then(Value::as_str) {
+                    Some("ack") => return Some(PushOutcome::Accepted(Some(reply))),
+                    Some("error") => {
+                        eprintln!("[agent] server rejected payload: {}", reply["detail"]);
+                        return Some(PushOutcome::Rejected);
+                    }
+                    _ => self.inbox.push(reply),
+                },
+                Ok(None) => {
+                    eprintln!("[agent] websocket ack timed out");
+                    self.drop_socket();
+                    return Some(PushOutcome::Unreachable);
+                }
+                Err(err) => {
+                    eprintln!("[agent] websocket closed: {err}");
+                    self.drop_socket();
+                    return Some(PushOutcome::Unreachable);
+                }
+            }
+        }
+    }
+
+    fn connect_socket(&mut self) {
+        let Some(mut url) = self.ws_url.clone() else {
+            return;
+        };
+        if self.reconnect_at.is_some_and(|t| Instant::now() < t) {
+            return;
+        }
+        url.query_pairs_mut().append_pair("encoding", &self.encoding);
+        match open_socket(&url, &self.token) {
+            Ok(socket) => {
+                eprintln!("[agent] websocket connected");
+                self.socket = Some(socket);
+                self.ws_failures = 0;
+                self.reconnect_at = None;
+                self.last_frame = Instant::now();
+            }
+            // 下一轮直接以 JSON 重连，无需退避
+            Err(SocketError::UnsupportedEncoding) if self.encoding != "json" => self.downgrade_encoding(),
+            Err(err) => {
+                let err = match err {
+                    SocketError::UnsupportedEncoding => "panel rejected the encoding".to_string(),
+                    SocketError::Other(err) => err,
+                };
+                self.ws_failures += 1;
+                let delay = (1u64 << self.ws_failures.min(6)).min(MAX_RECONNECT_DELAY);
+                eprintln!("[agent] websocket connect failed: {err}, retry in {delay}s");
+                self.reconnect_at = Some(Instant::now() + Duration::from_secs(delay));
+            }
+        }
+    }
+
+    fn drop_socket(&mut self) {
+        if let Some(mut socket) = self.socket.take() {
+            let _ = socket.close(None);
+        }
+    }
+
+    /// 读取一条面板消息，到 deadline 仍无消息返回 Ok(None)。
+    fn read_socket(&mut self, deadline: Instant) -> Result<Option<Value>, String> {
+        let socket = self.socket.as_mut().ok_or("not connected")?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let stream = match socket.get_mut() {
+                tungstenite::stream::MaybeTlsStream::Plain(s) => s,
+                tungstenite::stream::MaybeTlsStream::Rustls(s) => &mut s.sock,
+                _ => return Err("unsupported stream".into()),
+            };
+            stream.set_read_timeout(Some(remaining)).map_err(|e| e.to_string())?;
+            match socket.read() {
+                Ok(tungstenite::Message::Text(text)) => return Ok(serde_json::from_str(&text).ok()),
+                Ok(tungstenite::Message::Binary(data)) => {
+                    return Ok(decode_value(encoding_content_type(&self.encoding), &data))
+                }
+                Ok(tungstenite::Message::Close(_)) => return Err("closed by panel".into()),
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+
+    /// 等待到下一次采集，期间保持心跳并收取面板下发的消息。
+    fn wait(&mut self, duration: Duration) -> Vec<Value> {
+        let deadline = Instant::now() + duration;
+        while self.socket.is_some() && Instant::now() < deadline {
+            if self.last_frame.elapsed() >= HEARTBEAT_INTERVAL {
+                if let Some(socket) = self.socket.as_mut() {
+                    if let Err(err) = socket.send(tungstenite::Message::Ping(Vec::new())) {
+                        eprintln!("[agent] websocket heartbeat failed: {err}");
+                        self.drop_socket();
+                        break;
+                    }
+                }
+                self.last_frame = Instant::now();
+            }
+            match self.read_socket(deadline.min(self.last_frame + HEARTBEAT_INTERVAL)) {
+                Ok(Some(message)) => self.inbox.push(message),
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!("[agent] websocket closed: {err}");
+                    self.drop_socket();
+                }
+            }
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() {
+            thread::sleep(remaining);
+        }
+        std::mem::take(&mut self.inbox)
+    }
+
+    fn push_batch(&mut self, samples: &[Value]) -> PushOutcome {
+        let url = self.batch_url.clone();
+        let compression = self.compression.clone();
+        self.send(url, &json!(samples), compression.as_deref())
+    }
+
+    fn send(&mut self, url: Url, value: &Value, compression: Option<&str>) -> PushOutcome {
+        let outcome = self.send_encoded(url.clone(), value, compression);
+        if matches!(outcome, PushOutcome::Unsupported) && self.encoding != "json" {
+            self.downgrade_encoding();
+            return self.send_encoded(url, value, compression);
+        }
+        match outcome {
+            PushOutcome::Unsupported => PushOutcome::Rejected,
+            other => other,
+        }
+    }
+
+    /// 旧版面板只认 JSON；降级对 HTTP 与 WebSocket 都持续生效，已建立的连接按新编码重连。
+    fn downgrade_encoding(&mut self) {
+        eprintln!("[agent] panel does not accept {}, falling back to json", self.encoding);
+        self.encoding = "json".to_string();
+        self.drop_socket();
+    }
+
+    fn send_encoded(&self, url: Url, value: &Value, compression: Option<&str>) -> PushOutcome {
+        let content_type = encoding_content_type(&self.encoding);
+        let encoded = encode_value(&self.encoding, value).and_then(|raw| match compression {
+            Some("zstd") => zstd::encode_all(&raw[..], 3),
+            Some(_) => {
+                let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(&raw).and_then(|_| enc.finish())
+            }
+            None => Ok(raw),
+        });
+        let body = match encoded {
+            Ok(b) => b,
+            Err(err) => {
+                eprintln!("[agent] failed to encode payload: {err}");
+                return PushOutcome::Rejected;
+            }
+        };
+        let mut req = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .header(reqwest::header::ACCEPT, content_type)
+            .body(body);
+        if let Some(enc) = compression {
+            req = req.header(reqwest::header::CONTENT_ENCODING, enc);
+        }
+        match req.send() {
+            Ok(resp) if resp.status().is_success() => {
+                let reply_type = resp
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("application/json")
+                    .to_string();
+                let body = resp.bytes().ok().and_then(|b| decode_value(&reply_type, &b));
+                PushOutcome::Accepted(body)
+            }
+            Ok(resp) if resp.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                PushOutcome::Unsupported
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                eprintln!(
+                    "[agent] server rejected payload: {} {}",
+                    status,
+                    resp.text().unwrap_or_default()
+                );
+                if status.is_server_error() {
+                    PushOutcome::Unreachable
+                } else if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::METHOD_NOT_ALLOWED {
+                    PushOutcome::NotFound
+                } else {
+                    PushOutcome::Rejected
+                }
+            }
+            Err(err) => {
+                eprintln!("[agent] failed to push metrics: {err}");
+                PushOutcome::Unreachable
+            }
+        }
+    }
+
+    /// 按时间顺序批量发送队列中的样本，遇到失败即停止，剩余的留到下一轮。
+    /// 返回最后一次成功响应的内容。
+    fn flush_backlog(&mut self, backlog: &mut VecDeque<Value>) -> Option<Value> {
+        let mut last_body = None;
+        let mut sent = 0;
+        for _ in 0..BACKFILL_ROUNDS {
+            if backlog.is_empty() {
+                break;
+            }
+            let n = backlog.len().min(BACKFILL_BATCH);
+            let chunk: Vec<Value> = backlog.range(..n).cloned().collect();
+            let outcome = if self.batch_supported {
+                self.push_batch(&chunk)
+            } else {
+                PushOutcome::NotFound
+            };
+            match outcome {
+                PushOutcome::Accepted(body) => {
+                    backlog.drain(..n);
+                    sent += n;
+                    last_body = body;
+                }
+                // 旧版面板没有批量接口，记住结果，此后直接逐条上报
+                PushOutcome::NotFound => {
+                    if self.batch_supported {
+                        eprintln!("[agent] panel has no batch endpoint, sending buffered samples one by one");
+                        self.batch_supported = false;
+                    }
+                    for sample in chunk {
+                        match self.post_report(&sample) {
+                            PushOutcome::Accepted(body) => last_body = body.or(last_body),
+                            // 被拒绝的样本重试无意义，直接丢弃
+                            PushOutcome::Rejected | PushOutcome::NotFound | PushOutcome::Unsupported => {}
+                            PushOutcome::Unreachable => break,
+                        }
+                        backlog.pop_front();
+                        sent += 1;
+                    }
+                    break;
+                }
+                // 整批被拒（如 400、401），逐条重发同样会被拒，与单条上报一样丢弃
+                PushOutcome::Rejected => {
+                    eprintln!("[agent] panel rejected {n} buffered samples, dropping them");
+                    backlog.drain(..n);
+                    break;
+                }
+                PushOutcome::Unreachable | PushOutcome::Unsupported => break,
+            }
+        }
+        if sent > 0 && !backlog.is_empty() {
+            eprintln!("[agent] sent {sent} buffered samples, {} pending", backlog.len());
+        }
+        last_body
+    }
+}
+
+/// WebSocket 建连失败的原因；面板拒绝所用编码（HTTP 415）时改用 JSON 重连。
+enum SocketError {
+    UnsupportedEncoding,
+    Other(String),
+}
+
+impl<E: std::fmt::Display> From<E> for SocketError {
+    fn from(err: E) -> Self {
+        SocketError::Other(err.to_string())
+    }
+}
+
+fn open_socket(url: &Url, token: &str) -> Result<AgentSocket, SocketError> {
+    use tungstenite::client::IntoClientRequest;
+    let host = url.host_str().ok_or("missing host")?;
+    let port = url.port_or_known_default().ok_or("missing port")?;
+    let addr = (host, port).to_socket_addrs()?.next().ok_or("host not resolved")?;
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(10))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    // 握手阶段也需要超时，之后由 read_socket 按需调整
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut request = url.as_str().into_client_request()?;
+    let auth = format!("Bearer {token}")
+        .parse()
+        .map_err(|_| "token is not a valid header value")?;
+    request.headers_mut().insert(tungstenite::http::header::AUTHORIZATION, auth);
+    match tungstenite::client_tls(request, stream) {
+        Ok((socket, _)) => Ok(socket),
+        Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Http(resp)))
+            if resp.status() == tungstenite::http::StatusCode::UNSUPPORTED_MEDIA_TYPE =>
+        {
+            Err(SocketError::UnsupportedEncoding)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// 面板经 WebSocket 下发的消息。
+fn handle_panel_message(message: &Value, base: &Config, cfg: &mut Config, version: &mut String) {
+    match message.get("type").and_then(Value::as_str).unwrap_or("unknown") {
+        "config" => update_remote_config(base, cfg, version, message),
+        kind => eprintln!("[agent] ignoring panel message: {kind}"),
+    }
+}
+
+/// 面板下发 {version, settings}；版本未变时不重复应用。
+fn update_remote_config(base: &Config, cfg: &mut Config, version: &mut String, remote: &Value) {
+    let Some(next) = remote.get("version").and_then(Value::as_str) else {
+        return;
+    };
+    if next == version.as_str() {
+        return;
+    }
+    let empty = Map::new();
+    let settings = remote.get("settings").and_then(Value::as_object).unwrap_or(&empty);
+    *cfg = apply_remote_config(base, settings);
+    *version = next.to_string();
+    if next.is_empty() {
+        eprintln!("[agent] remote config cleared, using local settings");
+    } else {
+        eprintln!("[agent] applied remote config {next}");
+    }
+}
+
+/// 在本地配置上叠加面板下发的设置，未出现的键保持本地值。
+fn apply_remote_config(base: &Config, settings: &Map<String, Value>) -> Config {
+    let mut cfg = base.clone();
+    let strings = |value: &Value| -> Vec<String> {
+        value
+            .as_array()
+            .map(|items| items.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    for (key, value) in settings {
+        match key.as_str() {
+            "interval" => {
+                if let Some(n) = value.as_u64().filter(|n| *n > 0) {
+                    cfg.interval = n;
+                }
+            }
+            "top_n" => {
+                if let Some(n) = value.as_u64().filter(|n| *n > 0) {
+                    cfg.top_n = n as usize;
+                }
+            }
+            "flag" => {
+                if let Some(flag) = value.as_str() {
+                    cfg.flag = flag.to_string();
+                }
+            }
+            "skip_fs" => cfg.skip_fs = strings(value),
+            "net_include" => cfg.net_include = strings(value),
+            "net_exclude" => cfg.net_exclude = strings(value),
+            "disk_exclude" => cfg.disk_exclude = strings(value),
+            "collectors" => {
+                for (name, on) in value.as_object().into_iter().flatten() {
+                    if on.as_bool() == Some(false) {
+                        cfg.disabled.insert(name.clone());
+                        continue;
+                    }
+                    cfg.disabled.remove(name);
+                    // 本地未配置的 systemd / Docker 采集在开启时使用默认值；默认 Docker 套接字不存在时不开启
+                    match name.as_str() {
+                        "systemd" if cfg.systemd_bus.is_none() => cfg.systemd_bus = Some("system".into()),
+                        "containers" if cfg.docker_socket.is_none() => {
+                            if Path::new(DEFAULT_DOCKER_SOCKET).exists() {
+                                cfg.docker_socket = Some(DEFAULT_DOCKER_SOCKET.into());
+                            } else {
+                                eprintln!("[agent] remote config enables containers, but {DEFAULT_DOCKER_SOCKET} does not exist");
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            other => eprintln!("[agent] ignoring unknown remote setting: {other}"),
+        }
+    }
+    cfg
+}
+
+fn encoding_content_type(encoding: &str) -> &'static str {
+    match encoding {
+        "cbor" => "application/cbor",
+        "msgpack" => "application/msgpack",
+        _ => "application/json",
+    }
+}
+
+fn encode_value(encoding: &str, value: &Value) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        "cbor" => {
+            let mut buf = Vec::new();
+            ciborium::into_writer(value, &mut buf).map_err(|e| std::io::Error::other(e.to_string()))?;
+            Ok(buf)
+        }
+        "msgpack" => rmp_serde::to_vec(value).map_err(|e| std::io::Error::other(e.to_string())),
+        _ => serde_json::to_vec(value).map_err(std::io::Error::other),
+    }
+}
+
+fn decode_value(content_type: &str, body: &[u8]) -> Option<Value> {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    match essence {
+        "application/cbor" => ciborium::from_reader(body).ok(),
+        "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+            rmp_serde::from_slice(body).ok()
+        }
+        _ => serde_json::from_slice(body).ok(),
+    }
+}
+
+/// 可通过命令行、环境变量与配置文件设置的选项；配置文件的键为参数名中的 `-` 换成 `_`。
+struct ConfigOption {
+    name: &'static str,
+    env: &'static str,
+    /// 取值提示，为空表示开关
+    value: &'static str,
+    help: &'static str,
+}
+
+const CONFIG_OPTIONS: &[ConfigOption] = &[
+    ConfigOption { name: "config", env: "IMONITOR_CONFIG", value: "<path>", help: "config file, default /etc/imonitor/agent.toml" },
+    ConfigOption { name: "token", env: "IMONITOR_TOKEN", value: "<token>", help: "node token" },
+    ConfigOption { name: "endpoint", env: "IMONITOR_ENDPOINT", value: "<url>", help: "panel URL" },
+    ConfigOption { name: "interval", env: "IMONITOR_INTERVAL", value: "<secs>", help: "collection interval, default 3" },
+    ConfigOption { name: "flag", env: "IMONITOR_FLAG", value: "<emoji>", help: "node flag shown in the panel" },
+    ConfigOption { name: "skip-fs", env: "IMONITOR_SKIP_FS", value: "<list>", help: "filesystem types excluded from mounts" },
+    ConfigOption { name: "net-include", env: "IMONITOR_NET_INCLUDE", value: "<list>", help: "interfaces counted in traffic totals" },
+    ConfigOption { name: "net-exclude", env: "IMONITOR_NET_EXCLUDE", value: "<list>", help: "interfaces excluded from traffic totals" },
+    ConfigOption { name: "disk-exclude", env: "IMONITOR_DISK_EXCLUDE", value: "<list>", help: "devices excluded from disk I/O" },
+    ConfigOption { name: "top-n", env: "IMONITOR_TOP_N", value: "<n>", help: "top processes by CPU and memory, default 5" },
+    ConfigOption { name: "systemd-bus", env: "IMONITOR_SYSTEMD_BUS", value: "<system|session|off>", help: "systemd unit collection" },
+    ConfigOption { name: "docker-socket", env: "IMONITOR_DOCKER_SOCKET", value: "<path|off>", help: "Docker API socket" },
+    ConfigOption { name: "buffer-size", env: "IMONITOR_BUFFER_SIZE", value: "<n>", help: "samples kept while the panel is unreachable, default 600" },
+    ConfigOption { name: "flush-interval", env: "IMONITOR_FLUSH_INTERVAL", value: "<secs>", help: "send samples in batches every N seconds" },
+    ConfigOption { name: "compression", env: "IMONITOR_COMPRESSION", value: "<gzip|zstd|none>", help: "batch compression, default gzip" },
+    ConfigOption { name: "encoding", env: "IMONITOR_ENCODING", value: "<json|cbor|msgpack>", help: "report encoding, default json" },
+    ConfigOption { name: "transport", env: "IMONITOR_TRANSPORT", value: "<http|ws>", help: "report transport, default http" },
+    ConfigOption { name: "auto-update", env: "IMONITOR_AUTO_UPDATE", value: "", help: "install new agent builds from the panel" },
+    ConfigOption { name: "textfile-dir", env: "IMONITOR_TEXTFILE_DIR", value: "<dir>", help: "read custom metrics from *.prom files" },
+    ConfigOption { name: "statsd-listen", env: "IMONITOR_STATSD_LISTEN", value: "<addr|on|off>", help: "StatsD UDP listener, on = 127.0.0.1:8125" },
+];
+
+/// 只能在命令行使用的运行模式。
+const CLI_MODES: &[(&str, &str)] = &[
+    ("check", "validate the configuration and panel connectivity, then exit"),
+    ("once", "run a single collection cycle and exit"),
+    ("print", "print payloads to stdout instead of sending them"),
+    ("self-update", "install the panel's agent build and exit"),
+    ("version", "print the version and exit"),
+    ("help", "print this help and exit"),
+];
+
+const DEFAULT_CONFIG_FILE: &str = "/etc/imonitor/agent.toml";
+
+fn print_usage() {
+    println!("imonitor-agent {AGENT_VERSION}\n");
+    println!("Usage: agent [options]\n");
+    println!("Options (command line > environment > config file):");
+    for opt in CONFIG_OPTIONS {
+        let arg = format!("--{} {}", opt.name, opt.value);
+        println!("  {arg:<40} {}  [{}]", opt.help, opt.env);
+    }
+    println!("\nModes:");
+    for (name, help) in CLI_MODES {
+        println!("  {:<40} {help}", format!("--{name}"));
+    }
+}
+
+/// 选项的原始值及来源，来源用于错误提示。
+struct Setting {
+    value: String,
+    source: String,
+}
+
+impl Setting {
+    fn number(&self, min: u64) -> Result<u64, String> {
+        self.value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|n| *n >= min)
+            .ok_or_else(|| format!("{}: expected an integer >= {min}, got '{}'", self.source, self.value))
+    }
+
+    fn boolean(&self) -> Result<bool, String> {
+        match self.value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" | "" => Ok(false),
+            _ => Err(format!("{}: expected true or false, got '{}'", self.source, self.value)),
+        }
+    }
+
+    fn unsupported(&self, expected: &str) -> String {
+        format!("{}: unsupported value '{}', expected {expected}", self.source, self.value)
+    }
+}
+
+/// 读取 TOML 配置文件，列表可写成数组或逗号分隔的字符串。
+fn read_config_file(path: &Path) -> Result<(HashMap<&'static str, Setting>, Vec<PluginSpec>), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let table: toml::Table = text.parse().map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut settings = HashMap::new();
+    let mut plugins = Vec::new();
+    for (key, value) in table {
+        let source = format!("{}: {key}", path.display());
+        if key == "plugins" {
+            plugins = parse_plugins(&value, &source)?;
+            continue;
+        }
+        let opt = CONFIG_OPTIONS
+            .iter()
+            .find(|o| o.name != "config" && o.name.replace('-', "_") == key)
+            .ok_or_else(|| format!("{source}: unknown key"))?;
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(n) => n.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("{source}: expected an array of strings"))?
+                .join(","),
+            _ => return Err(format!("{source}: unsupported value type")),
+        };
+        settings.insert(opt.name, Setting { value, source });
+    }
+    Ok((settings, plugins))
+}
+
+/// [[plugins]]：name、command 必填，interval 缺省跟随采集间隔，timeout 缺省 10 秒。
+fn parse_plugins(value: &toml::Value, source: &str) -> Result<Vec<PluginSpec>, String> {
+    let items = value
+        .as_array()
+        .ok_or_else(|| format!("{source}: expected [[plugins]] tables"))?;
+    let mut plugins: Vec<PluginSpec> = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let at = format!("{source}[{i}]");
+        let table = item.as_table().ok_or_else(|| format!("{at}: expected a table"))?;
+        if let Some(key) = table
+            .keys()
+            .find(|k| !matches!(k.as_str(), "name" | "command" | "interval" | "timeout"))
+        {
+            return Err(format!("{at}: unknown key {key}"));
+        }
+        let name = table
+            .get("name")
+            .and_then(toml::Value::as_str)
+            .filter(|n| !n.is_empty())
+            .ok_or_else(|| format!("{at}: name required"))?
+            .to_string();
+        if plugins.iter().any(|p| p.name == name) {
+            return Err(format!("{at}: duplicate plugin name {name}"));
+        }
+        let command = match table.get("command") {
+            Some(toml::Value::String(cmd)) if !cmd.trim().is_empty() => {
+                vec!["sh".to_string(), "-c".to_string(), cmd.clone()]
+            }
+            Some(toml::Value::Array(argv)) if !argv.is_empty() => argv
+                .iter()
+                .map(|a| a.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("{at}: command must be a string or an array of strings"))?,
+            _ => return Err(format!("{at}: command must be a string or an array of strings")),
+        };
+        let secs = |key: &str| -> Result<Option<u64>, String> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(v) => v
+                    .as_integer()
+                    .filter(|n| *n > 0)
+                    .map(|n| Some(n as u64))
+                    .ok_or_else(|| format!("{at}: {key} must be a positive integer")),
+            }
+        };
+        plugins.push(PluginSpec {
+            name,
+            command,
+            // 0 表示跟随采集间隔，在 load_config 中确定
+            interval: Duration::from_secs(secs("interval")?.unwrap_or(0)),
+            timeout: Duration::from_secs(secs("timeout")?.unwrap_or(DEFAULT_PLUGIN_TIMEOUT)),
+        });
+    }
+    Ok(plugins)
+}
+
+/// 命令行解析结果。
+enum Invocation {
+    Help,
+    Version,
+    Run(Box<Config>),
+}
+
+/// 合并命令行、环境变量（`env_var`）与配置文件，优先级依次降低。
+///
+/// --help / --version 先于其它参数处理，参数有误或缺少令牌时也能查看。
+fn load_config(args: &[String], env_var: impl Fn(&str) -> Option<String>) -> Result<Invocation, String> {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        return Ok(Invocation::Help);
+    }
+    if args.iter().any(|arg| arg == "--version") {
+        return Ok(Invocation::Version);
+    }
+    let mut cli: HashMap<&'static str, Setting> = HashMap::new();
+    let mut modes: HashSet<&'static str> = HashSet::new();
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        let body = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("unexpected argument '{arg}' (see --help)"))?;
+        let (name, inline) = match body.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (body, None),
+        };
+        if let Some((mode, _)) = CLI_MODES.iter().find(|(m, _)| *m == name) {
+            if inline.is_some() {
+                return Err(format!("--{name} does not take a value"));
+            }
+            modes.insert(mode);
+            continue;
+        }
+        let opt = CONFIG_OPTIONS
+            .iter()
+            .find(|o| o.name == name)
+            .ok_or_else(|| format!("unknown option --{name} (see --help)"))?;
+        let value = match inline {
+            Some(value) => value,
+            None if opt.value.is_empty() => "true".to_string(),
+            None => args.next().ok_or_else(|| format!("--{name} requires a value"))?,
+        };
+        cli.insert(opt.name, Setting { value, source: format!("--{name}") });
+    }
+
+    // 显式指定的配置文件必须存在，默认路径不存在时忽略
+    let config_file = match cli.get("config").map(|s| s.value.clone()).or_else(|| env_var("IMONITOR_CONFIG")) {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        Some(_) => None,
+        None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.exists()),
+    };
+    let (mut settings, mut plugins) = match &config_file {
+        Some(path) => read_config_file(path)?,
+        None => (HashMap::new(), Vec::new()),
+    };
+    for opt in CONFIG_OPTIONS {
+        if let Some(value) = env_var(opt.env) {
+            settings.insert(opt.name, Setting { value, source: opt.env.to_string() });
+        }
+    }
+    settings.extend(cli);
+    let get = |name: &str| settings.get(name);
+    let text = |name: &str| get(name).map(|s| s.value.clone());
+    // 未指定时使用内置列表，显式传空字符串则不过滤
+    let list = |name: &str, default: &[&str]| match get(name) {
+        Some(s) => split_list(&s.value),
+        None => default.iter().map(|s| s.to_string()).collect(),
+    };
+
+    let check = modes.contains("check");
+    let once = modes.contains("once");
+    let print = modes.contains("print");
+    let self_update = modes.contains("self-update");
+    // 只打印不发送时无需令牌与面板地址
+    let token = match text("token") {
+        Some(token) if !token.is_empty() => token,
+        _ if print || self_update => String::new(),
+        _ => return Err("missing token (--token, IMONITOR_TOKEN or token in the config file)".into()),
+    };
+    let endpoint = match get("endpoint") {
+        Some(s) => {
+            let url = Url::parse(&s.value).map_err(|e| format!("{}: invalid URL '{}': {e}", s.source, s.value))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(s.unsupported("an http:// or https:// URL"));
+            }
+            s.value.clone()
+        }
+        None if print => "http://127.0.0.1".to_string(),
+        None => return Err("missing endpoint (--endpoint, IMONITOR_ENDPOINT or endpoint in the config file)".into()),
+    };
+    let number = |name: &str, min: u64, default: u64| get(name).map_or(Ok(default), |s| s.number(min));
+    let flag_on = |name: &str| get(name).map_or(Ok(false), Setting::boolean);
+
+    let compression = match get("compression") {
+        None => Some("gzip".to_string()),
+        Some(s) => match s.value.to_ascii_lowercase().as_str() {
+            v @ ("gzip" | "zstd") => Some(v.to_string()),
+            "none" | "off" | "" => None,
+            _ => return Err(s.unsupported("gzip, zstd or none")),
+        },
+    };
+    let encoding = match get("encoding") {
+        None => "json".to_string(),
+        Some(s) => match s.value.to_ascii_lowercase().as_str() {
+            "" | "json" => "json".to_string(),
+            v @ ("cbor" | "msgpack") => v.to_string(),
+            _ => return Err(s.unsupported("json, cbor or msgpack")),
+        },
+    };
+    // 单次运行与打印模式只走 HTTP，不建立长连接
+    let transport = match get("transport") {
+        None => "http".to_string(),
+        Some(s) => match s.value.as_str() {
+            "" | "http" => "http".to_string(),
+            "ws" if once || print => "http".to_string(),
+            "ws" => "ws".to_string(),
+            _ => return Err(s.unsupported("http or ws")),
+        },
+    };
+
+    let interval = number("interval", 1, DEFAULT_INTERVAL)?;
+    let statsd_listen = match get("statsd-listen") {
+        None => None,
+        Some(s) => match s.value.trim().to_ascii_lowercase().as_str() {
+            "" | "off" | "false" | "0" => None,
+            "on" | "true" | "1" => Some(DEFAULT_STATSD_ADDR.to_string()),
+            addr if addr.parse::<std::net::SocketAddr>().is_ok() => Some(addr.to_string()),
+            _ => return Err(s.unsupported("host:port, on or off")),
+        },
+    };
+    for plugin in &mut plugins {
+        if plugin.interval.is_zero() {
+            plugin.interval = Duration::from_secs(interval);
+        }
+    }
+
+    Ok(Invocation::Run(Box::new(Config {
+        token,
+        endpoint,
+        interval,
+        flag: text("flag").unwrap_or_else(|| DEFAULT_FLAG.to_string()),
+        skip_fs: list("skip-fs", &[PSEUDO_FS, NETWORK_FS].concat()),
+        net_include: list("net-include", &[]),
+        net_exclude: list("net-exclude", DEFAULT_NET_EXCLUDE),
+        disk_exclude: list("disk-exclude", DEFAULT_DISK_EXCLUDE),
+        top_n: number("top-n", 1, DEFAULT_TOP_N as u64)? as usize,
+        // system（默认）/ session，off 关闭采集
+        systemd_bus: match text("systemd-bus").as_deref() {
+            Some("off") | Some("") => None,
+            Some(v) => Some(v.to_string()),
+            None => Some("system".to_string()),
+        },
+        // 未指定时仅在默认 socket 存在时启用，off 关闭
+        docker_socket: match text("docker-socket").as_deref() {
+            Some("off") | Some("") => None,
+            Some(v) => Some(v.to_string()),
+            None if Path::new(DEFAULT_DOCKER_SOCKET).exists() => {
+                Some(DEFAULT_DOCKER_SOCKET.to_string())
+            }
+            None => None,
+        },
+        // 0 表示不缓存离线样本
+        buffer_size: number("buffer-size", 0, DEFAULT_BUFFER_SIZE as u64)? as usize,
+        compression,
+        // 0 表示每次采集后立即上报；WebSocket 与单次运行不使用批量上报
+        flush_interval: if transport == "ws" || once {
+            0
+        } else {
+            number("flush-interval", 0, 0)?
+        },
+        encoding,
+        transport,
+        disabled: HashSet::new(),
+        auto_update: flag_on("auto-update")?,
+        self_update,
+        check,
+        once,
+        print,
+        config_file,
+        plugins,
+        textfile_dir: text("textfile-dir").filter(|d| !d.is_empty()),
+        statsd_listen,
+    })))
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn detect_ip() -> String {
+    if let Ok(sock) = UdpSocket::bind("0.0.0.0:0") {
+        if sock.connect("8.8.8.8:80").is_ok() {
+            if let Ok(addr) = sock.local_addr() {
+                return addr.ip().to_string();
+            }
+        }
+    }
+    String::new()
+}
+
+fn get_hostname() -> String {
+    unsafe {
+        let mut buf = [0u8; 256];
+        if libc::gethostname(buf.as_mut_ptr() as *mut c_char, buf.len()) == 0 {
+            if let Ok(cstr) = CStr::from_ptr(buf.as_ptr() as *const c_char).to_str() {
+                return cstr.trim_end_matches(char::from(0)).to_string();
+            }
+        }
+    }
+    "unknown".to_string()
+}
+
+/// 返回汇总行与各 cpuN 行的 (N, 计数)，按出现顺序排列；离线的 CPU 不出现在 /proc/stat 中。
+fn read_cpu_times() -> Option<(CpuTimes, Vec<(u32, CpuTimes)>)> {
+    let file = File::open("/proc/stat").ok()?;
+    let mut total = None;
+    let mut cores = Vec::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let mut parts = line.split_whitespace();
+        let label = match parts.next() {
+            Some(l) if l.starts_with("cpu") => l,
+            // cpu 行总在文件开头，遇到其它行即可结束
+            _ => break,
+        };
+        let nums: Vec<u64> = parts.filter_map(|p| p.parse::<u64>().ok()).collect();
+        if nums.len() < 8 {
+            continue;
+        }
+        let times = CpuTimes {
+            user: nums[0],
+            nice: nums[1],
+            system: nums[2],
+            idle: nums[3],
+            iowait: nums[4],
+            irq: nums[5],
+            softirq: nums[6],
+            steal: nums[7],
+        };
+        if label == "cpu" {
+            total = Some(times);
+        } else if let Ok(id) = label["cpu".len()..].parse::<u32>() {
+            cores.push((id, times));
+        }
+    }
+    Some((total?, cores))
+}
+
+fn compute_cpu_breakdown(prev: CpuTimes, curr: CpuTimes) -> Value {
+    let d = |a: u64, b: u64| b.saturating_sub(a) as f64;
+    let fields = [
+        ("user", d(prev.user, curr.user)),
+        ("nice", d(prev.nice, curr.nice)),
+        ("system", d(prev.system, curr.system)),
+        ("idle", d(prev.idle, curr.idle)),
+        ("iowait", d(prev.iowait, curr.iowait)),
+        ("irq", d(prev.irq, curr.irq)),
+        ("softirq", d(prev.softirq, curr.softirq)),
+        ("steal", d(prev.steal, curr.steal)),
+    ];
+    let total: f64 = fields.iter().map(|(_, v)| v).sum();
+    let mut map = Map::new();
+    for (name, value) in fields {
+        let pct = if total > 0.0 { value / total * 100.0 } else { 0.0 };
+        map.insert(name.into(), json!(round2(pct)));
+    }
+    Value::Object(map)
+}
+
+/// 按 cpuN 编号匹配前后两次采样：CPU 热插拔后行数会变化，按位置对应会错位。
+/// 刚上线、尚无上一轮数据的 CPU 记为 0。
+fn per_core_usage(prev: &[(u32, CpuTimes)], curr: &[(u32, CpuTimes)]) -> Vec<f64> {
+    curr.iter()
+        .map(|(id, c)| {
+            prev.iter()
+                .find(|(p, _)| p == id)
+                .map_or(0.0, |(_, p)| round2(compute_cpu_usage(*p, *c)))
+        })
+        .collect()
+}
+
+fn compute_cpu_usage(prev: CpuTimes, curr: CpuTimes) -> f64 {
+    let prev_idle = prev.idle + prev.iowait;
+    let curr_idle = curr.idle + curr.iowait;
+    let prev_total = prev_idle + prev.user + prev.nice + prev.system + prev.irq + prev.softirq + prev.steal;
+    let curr_total = curr_idle + curr.user + curr.nice + curr.system + curr.irq + curr.softirq + curr.steal;
+    let totald = curr_total.saturating_sub(prev_total) as f64;
+    let idled = curr_idle.saturating_sub(prev_idle) as f64;
+    if totald <= 0.0 {
+        0.0
+    } else {
+        (1.0 - idled / totald) * 100.0
+    }
+}
+
+fn read_meminfo(path: &str) -> Option<MemInfo> {
+    let file = File::open(path).ok()?;
+    let mut info = MemInfo::default();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let mut parts = line.split_whitespace();
+        let (key, value) = match (parts.next(), parts.next().and_then(|v| v.parse::<u64>().ok())) {
+            (Some(k), Some(v)) => (k.trim_end_matches(':'), v),
+            _ => continue,
+        };
+        // 除 HugePages_* 为页数外，其余字段单位均为 kB
+        match key {
+            "MemTotal" => info.total = value * 1024,
+            "MemFree" => info.free = value * 1024,
+            "MemAvailable" => info.available = value * 1024,
+            "Buffers" => info.buffers = value * 1024,
+            "Cached" => info.cached = value * 1024,
+            "Shmem" => info.shared = value * 1024,
+            "Dirty" => info.dirty = value * 1024,
+            "SwapTotal" => info.swap_total = value * 1024,
+            "SwapFree" => info.swap_free = value * 1024,
+            "HugePages_Total" => info.hugepages_total = value,
+            "HugePages_Free" => info.hugepages_free = value,
+            "Hugepagesize" => info.hugepage_size = value * 1024,
+            _ => {}
+        }
+    }
+    if info.total == 0 {
+        None
+    } else {
+        Some(info)
+    }
+}
+
+/// 所有统计在内的挂载点合计的使用率，与各挂载点的 used_percent 口径一致。
+fn total_disk_percent(mounts: &[MountUsage]) -> Option<f64> {
+    let used: u64 = mounts.iter().map(|m| m.usage.used).sum();
+    let capacity: u64 = mounts.iter().map(|m| m.usage.used + m.usage.free).sum();
+    (capacity > 0).then(|| percent(used, capacity))
+}
+
+/// 没有可统计的挂载点时（如容器内根目录为 overlay）退回根目录的使用率。
+fn read_disk_percent() -> Option<f64> {
+    let usage = read_fs_usage("/")?;
+    let used = usage.total.saturating_sub(usage.free);
+    Some((used as f64 / usage.total as f64) * 100.0)
+}
+
+fn read_fs_usage(path: &str) -> Option<FsUsage> {
+    let cpath = CString::new(path).ok()?;
+    unsafe {
+        let mut vfs: statvfs = std::mem::zeroed();
+        if statvfs(cpath.as_ptr(), &mut vfs) != 0 {
+            return None;
+        }
+        let frsize = if vfs.f_frsize > 0 { vfs.f_frsize } else { vfs.f_bsize } as u64;
+        let blocks = vfs.f_blocks as u64;
+        if blocks == 0 {
+            return None;
+        }
+        let files = vfs.f_files as u64;
+        Some(FsUsage {
+            total: blocks * frsize,
+            used: blocks.saturating_sub(vfs.f_bfree as u64) * frsize,
+            free: vfs.f_bavail as u64 * frsize,
+            inodes_total: files,
+            inodes_used: files.saturating_sub(vfs.f_ffree as u64),
+        })
+    }
+}
+
+/// 仍卡在 statvfs 中的挂载点，返回前不再为其启动新线程。
+static STALLED_MOUNTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// 在独立线程中执行 statvfs，超时即放弃；失联的挂载点只会卡住该线程，不影响采集循环。
+fn read_fs_usage_with_timeout(path: &str) -> Option<FsUsage> {
+    {
+        let mut stalled = STALLED_MOUNTS.lock().unwrap();
+        if stalled.iter().any(|p| p == path) {
+            return None;
+        }
+        stalled.push(path.to_string());
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let owned = path.to_string();
+    thread::spawn(move || {
+        let usage = read_fs_usage(&owned);
+        STALLED_MOUNTS.lock().unwrap().retain(|p| p != &owned);
+        let _ = tx.send(usage);
+    });
+    match rx.recv_timeout(STATVFS_TIMEOUT) {
+        Ok(usage) => usage,
+        Err(_) => {
+            eprintln!("[agent] statvfs on {path} timed out, skipping until it returns");
+            None
+        }
+    }
+}
+
+fn read_mounts(mountinfo: &str, skip_fs: &[String]) -> Vec<MountUsage> {
+    let file = match File::open(mountinfo) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    let mut seen_devices: Vec<String> = Vec::new();
+    let mut mounts = Vec::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        // 格式: id parent major:minor root mount_point options [optional...] - fstype source super_options
+        let (left, right) = match line.split_once(" - ") {
+            Some(v) => v,
+            None => continue,
+        };
+        let left: Vec<&str> = left.split_whitespace().collect();
+        let right: Vec<&str> = right.split_whitespace().collect();
+        if left.len() < 5 || right.len() < 2 {
+            continue;
+        }
+        let fs_type = right[0];
+        if skip_fs.iter().any(|s| s == fs_type) {
+            continue;
+        }
+        // 同一块设备的 bind mount 只统计第一次出现的挂载点
+        let dev_id = left[2].to_string();
+        if seen_devices.contains(&dev_id) {
+            continue;
+        }
+        let mount_point = unescape_mount_path(left[4]);
+        let usage = if NETWORK_FS.contains(&fs_type) {
+            read_fs_usage_with_timeout(&mount_point)
+        } else {
+            read_fs_usage(&mount_point)
+        };
+        let usage = match usage {
+            Some(u) => u,
+            None => continue,
+        };
+        seen_devices.push(dev_id);
+        mounts.push(MountUsage {
+            mount_point,
+            device: unescape_mount_path(right[1]),
+            fs_type: fs_type.to_string(),
+            usage,
+        });
+    }
+    mounts
+}
+
+fn unescape_mount_path(raw: &str) -> String {
+    // mountinfo 中空格等字符以 \040 形式的八进制转义出现
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 3 < bytes.len()
+            && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b))
+        {
+            let code = (bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0');
+            out.push(code);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn mount_to_json(m: &MountUsage) -> Value {
+    let u = &m.usage;
+    let capacity = u.used + u.free;
+    json!({
+        "mount": m.mount_point,
+        "device": m.device,
+        "fstype": m.fs_type,
+        "total": u.total,
+        "used": u.used,
+        "free": u.free,
+        "used_percent": round2(percent(u.used, capacity)),
+        "inodes_total": u.inodes_total,
+        "inodes_used": u.inodes_used,
+        "inodes_percent": round2(percent(u.inodes_used, u.inodes_total)),
+    })
+}
+
+fn read_diskstats(path: &str, exclude: &[String]) -> Vec<DiskStat> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    // /sys/block 只列出整块设备，借此跳过分区；读不到 sysfs 时不做过滤
+    let whole_disks: Option<Vec<String>> = std::fs::read_dir("/sys/block").ok().map(|dir| {
+        dir.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect()
+    });
+    let mut result = Vec::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 14 {
+            continue;
+        }
+        let name = parts[2];
+        if exclude.iter().any(|p| glob_match(p, name)) {
+            continue;
+        }
+        // sysfs 中设备名里的 '/' 被替换成了 '!'（如 cciss!c0d0）
+        let sys_name = name.replace('/', "!");
+        if let Some(disks) = &whole_disks {
+            if !disks.contains(&sys_name) {
+                continue;
+            }
+        }
+        let num = |i: usize| parts[i].parse::<u64>().unwrap_or(0);
+        result.push(DiskStat {
+            name: name.to_string(),
+            reads: num(3),
+            sectors_read: num(5),
+            read_ms: num(6),
+            writes: num(7),
+            sectors_written: num(9),
+            write_ms: num(10),
+            io_ms: num(12),
+        });
+    }
+    result
+}
+
+/// `elapsed_sec` 为两次读取 /proc/diskstats 之间实际经过的秒数。
+fn compute_disk_io(prev: &DiskStat, curr: &DiskStat, elapsed_sec: f64) -> Value {
+    let secs = elapsed_sec.max(0.001);
+    let reads = curr.reads.saturating_sub(prev.reads);
+    let writes = curr.writes.saturating_sub(prev.writes);
+    let read_bytes = curr.sectors_read.saturating_sub(prev.sectors_read) * SECTOR_SIZE;
+    let write_bytes = curr.sectors_written.saturating_sub(prev.sectors_written) * SECTOR_SIZE;
+    let wait_ms = curr.read_ms.saturating_sub(prev.read_ms) + curr.write_ms.saturating_sub(prev.write_ms);
+    let io_ms = curr.io_ms.saturating_sub(prev.io_ms) as f64;
+    let ops = reads + writes;
+    let await_ms = if ops > 0 { wait_ms as f64 / ops as f64 } else { 0.0 };
+    let util = (io_ms / (secs * 1000.0) * 100.0).min(100.0);
+    json!({
+        "device": curr.name,
+        "read_bytes_per_sec": (read_bytes as f64 / secs).round() as u64,
+        "write_bytes_per_sec": (write_bytes as f64 / secs).round() as u64,
+        "read_iops": round2(reads as f64 / secs),
+        "write_iops": round2(writes as f64 / secs),
+        "await_ms": round2(await_ms),
+        "util_percent": round2(util),
+    })
+}
+
+fn read_processes(proc_root: &str) -> Vec<ProcSample> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    let entries = match std::fs::read_dir(proc_root) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    let mut result = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let pid = match entry.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // 进程可能在扫描过程中退出，读失败直接跳过
+        let stat = match std::fs::read_to_string(format!("{proc_root}/{pid}/stat")) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        // comm 可能包含空格和括号，以最后一个 ')' 为界
+        let (open, close) = match (stat.find('('), stat.rfind(')')) {
+            (Some(o), Some(c)) if c > o => (o, c),
+            _ => continue,
+        };
+        let name = stat[open + 1..close].to_string();
+        let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+        // fields[0] 对应 stat 的第 3 列 (state)
+        if fields.len() < 20 {
+            continue;
+        }
+        let num = |i: usize| fields[i].parse::<u64>().unwrap_or(0);
+        let rss_pages = std::fs::read_to_string(format!("{proc_root}/{pid}/statm"))
+            .ok()
+            .and_then(|s| s.split_whitespace().nth(1).and_then(|v| v.parse::<u64>().ok()))
+            .unwrap_or(0);
+        result.push(ProcSample {
+            pid,
+            name,
+            state: fields[0].chars().next().unwrap_or('?'),
+            threads: num(17),
+            cpu_ticks: num(11) + num(12),
+            start_time: num(19),
+            rss: rss_pages * page_size,
+        });
+    }
+    result
+}
+
+/// `elapsed_sec` 为与 `prev` 对应的上一次扫描到本次之间实际经过的秒数。
+fn summarize_processes(
+    procs: &[ProcSample],
+    prev: &HashMap<u32, (u64, u64)>,
+    elapsed_sec: f64,
+    top_n: usize,
+) -> Value {
+    let clk_tck = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+    let mut counts = [0u64; 4];
+    let mut threads = 0u64;
+    let mut with_cpu: Vec<(&ProcSample, f64)> = Vec::with_capacity(procs.len());
+    for p in procs {
+        threads += p.threads;
+        match p.state {
+            'R' => counts[0] += 1,
+            'D' => counts[1] += 1,
+            'Z' => counts[2] += 1,
+            'S' | 'I' => counts[3] += 1,
+            _ => {}
+        }
+        // pid 被复用时 starttime 不同，不能与上一轮相减
+        let cpu = match prev.get(&p.pid) {
+            Some((start, ticks)) if *start == p.start_time => {
+                p.cpu_ticks.saturating_sub(*ticks) as f64 / (elapsed_sec.max(0.001) * clk_tck) * 100.0
+            }
+            _ => 0.0,
+        };
+        with_cpu.push((p, cpu));
+    }
+    let to_json = |(p, cpu): &(&ProcSample, f64)| {
+        json!({"pid": p.pid, "name": p.name, "cpu": round2(*cpu), "rss": p.rss})
+    };
+    with_cpu.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let top_cpu: Vec<Value> = with_cpu.iter().take(top_n).map(to_json).collect();
+    with_cpu.sort_by_key(|(p, _)| std::cmp::Reverse(p.rss));
+    let top_memory: Vec<Value> = with_cpu.iter().take(top_n).map(to_json).collect();
+    json!({
+        "total": procs.len(),
+        "threads": threads,
+        "running": counts[0],
+        "blocked": counts[1],
+        "zombie": counts[2],
+        "sleeping": counts[3],
+        "top_cpu": top_cpu,
+        "top_memory": top_memory,
+    })
+}
+
+fn parse_watches(list: &[Value]) -> Vec<Watch> {
+    let mut result = Vec::new();
+    for item in list {
+        let field = |key: &str| item.get(key).and_then(Value::as_str).filter(|v| !v.is_empty());
+        let (id, kind, name) = match (field("id"), field("kind"), field("name")) {
+            (Some(id), Some(kind), Some(name)) => (id, kind, name),
+            _ => continue,
+        };
+        let cmdline = match field("cmdline").map(Regex::new) {
+            Some(Ok(re)) => Some(re),
+            Some(Err(err)) => {
+                eprintln!("[agent] invalid cmdline regex for watch {name}: {err}");
+                continue;
+            }
+            None => None,
+        };
+        result.push(Watch {
+            id: id.to_string(),
+            kind: kind.to_string(),
+            name: name.to_string(),
+            cmdline,
+            pidfile: field("pidfile").map(|v| v.to_string()),
+        });
+    }
+    result
+}
+
+fn check_watches(
+    watches: &[Watch],
+    states: &mut HashMap<String, WatchState>,
+    proc_root: &str,
+    procs: &[ProcSample],
+    bus: &mut Option<zbus::blocking::Connection>,
+    bus_kind: &str,
+) -> Vec<Value> {
+    let mut report = Vec::with_capacity(watches.len());
+    for watch in watches {
+        let state = states.entry(watch.id.clone()).or_default();
+        let entry = if watch.kind == "systemd" {
+            let (active, sub, restarts) = systemd_unit_state(bus, bus_kind, &watch.name);
+            json!({
+                "id": watch.id,
+                "kind": watch.kind,
+                "name": watch.name,
+                "status": if active == "active" || active == "reloading" { "ok" } else { "down" },
+                "state": format!("{active}/{sub}"),
+                "restarts": restarts,
+            })
+        } else {
+            let matched = match_watch_processes(watch, proc_root, procs);
+            let up = !matched.is_empty();
+            // 进程集合整体换新，或下线后重新出现，都记一次重启
+            let replaced =
+                !state.pids.is_empty() && up && !matched.iter().any(|m| state.pids.contains(m));
+            let revived = state.pids.is_empty() && state.seen_up && up;
+            if replaced || revived {
+                state.restarts += 1;
+            }
+            state.seen_up |= up;
+            state.pids = matched;
+            json!({
+                "id": watch.id,
+                "kind": watch.kind,
+                "name": watch.name,
+                "status": if up { "ok" } else { "down" },
+                "pids": state.pids.iter().map(|(pid, _)| *pid).collect::<Vec<u32>>(),
+                "restarts": state.restarts,
+            })
+        };
+        report.push(entry);
+    }
+    report
+}
+
+fn match_watch_processes(watch: &Watch, proc_root: &str, procs: &[ProcSample]) -> Vec<(u32, u64)> {
+    if let Some(pidfile) = &watch.pidfile {
+        let pid = std::fs::read_to_string(pidfile)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok());
+        return procs
+            .iter()
+            .filter(|p| Some(p.pid) == pid && p.state != 'Z')
+            .map(|p| (p.pid, p.start_time))
+            .collect();
+    }
+    procs
+        .iter()
+        .filter(|p| p.state != 'Z')
+        .filter(|p| match &watch.cmdline {
+            Some(re) => std::fs::read(format!("{proc_root}/{}/cmdline", p.pid))
+                .map(|raw| {
+                    let cmd = String::from_utf8_lossy(&raw).replace('\0', " ");
+                    re.is_match(cmd.trim_end())
+                })
+                .unwrap_or(false),
+            // comm 最长 15 个字符，超长进程名按前缀比较
+            None => p.name == watch.name || (p.name.len() == 15 && watch.name.starts_with(&p.name)),
+        })
+        .map(|p| (p.pid, p.start_time))
+        .collect()
+}
+
+/// 按需连接 systemd 所在的 D-Bus；总线不可用（非 systemd 系统、容器内）时返回 None，
+/// 下一轮再尝试重连。
+fn systemd_connection<'a>(
+    bus: &'a mut Option<zbus::blocking::Connection>,
+    kind: &str,
+) -> Option<&'a zbus::blocking::Connection> {
+    if bus.is_none() {
+        let conn = if kind == "session" {
+            zbus::blocking::Connection::session()
+        } else {
+            zbus::blocking::Connection::system()
+        };
+        *bus = conn.ok();
+    }
+    bus.as_ref()
+}
+
+/// 通过 D-Bus 调用 systemd 的 ListUnits，汇总失败单元。
+fn read_systemd_units(bus: &mut Option<zbus::blocking::Connection>, kind: &str) -> Option<Value> {
+    let conn = systemd_connection(bus, kind)?;
+    let reply = conn.call_method(
+        Some("org.freedesktop.systemd1"),
+        "/org/freedesktop/systemd1",
+        Some("org.freedesktop.systemd1.Manager"),
+        "ListUnits",
+        &(),
+    );
+    let units: Vec<UnitRow> = match reply.and_then(|msg| msg.body().deserialize()) {
+        Ok(units) => units,
+        Err(err) => {
+            eprintln!("[agent] systemd ListUnits failed: {err}");
+            *bus = None;
+            return None;
+        }
+    };
+    Some(summarize_units(&units, |path| unit_property_u64(conn, path, "StateChangeTimestamp")))
+}
+
+/// 把 ListUnits 的结果汇总为上报格式；`state_changed` 查询失败单元的 StateChangeTimestamp。
+fn summarize_units(
+    units: &[UnitRow],
+    state_changed: impl Fn(&OwnedObjectPath) -> Option<u64>,
+) -> Value {
+    let mut active = 0u64;
+    let mut failed = Vec::new();
+    for (name, description, _load, active_state, sub_state, _, path, _, _, _) in units {
+        match active_state.as_str() {
+            "active" => active += 1,
+            "failed" => {
+                // StateChangeTimestamp 为微秒级 Unix 时间戳，0 表示未知
+                let since = state_changed(path).filter(|v| *v > 0).map(|v| v / 1_000_000);
+                failed.push(json!({
+                    "name": name,
+                    "description": description,
+                    "sub_state": sub_state,
+                    "since": since,
+                }));
+            }
+            _ => {}
+        }
+    }
+    json!({
+        "units": units.len(),
+        "active": active,
+        "failed": failed.len(),
+        "failed_units": failed,
+    })
+}
+
+fn unit_property(
+    conn: &zbus::blocking::Connection,
+    path: &OwnedObjectPath,
+    interface: &str,
+    prop: &str,
+) -> Option<OwnedValue> {
+    let reply = conn
+        .call_method(
+            Some("org.freedesktop.systemd1"),
+            path.as_str(),
+            Some("org.freedesktop.DBus.Properties"),
+            "Get",
+            &(interface, prop),
+        )
+        .ok()?;
+    reply.body().deserialize().ok()
+}
+
+fn unit_property_u64(
+    conn: &zbus::blocking::Connection,
+    path: &OwnedObjectPath,
+    prop: &str,
+) -> Option<u64> {
+    u64::try_from(unit_property(conn, path, "org.freedesktop.systemd1.Unit", prop)?).ok()
+}
+
+/// 在 unix socket 上发起一次 HTTP/1.0 GET（Docker Engine API），返回解析后的 JSON。
+fn unix_http_get(socket: &str, path: &str) -> Result<Value, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("connect {socket}: {e}"))?;
+    stream.set_read_timeout(Some(DOCKER_TIMEOUT)).ok();
+    stream.set_write_timeout(Some(DOCKER_TIMEOUT)).ok();
+    let request =
+        format!("GET {path} HTTP/1.0\r\nHost: docker\r\nAccept: application/json\r\n\r\n");
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).map_err(|e| e.to_string())?;
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("malformed http response")?;
+    let head = String::from_utf8_lossy(&raw[..split]).to_ascii_lowercase();
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if !status.starts_with('2') {
+        return Err(format!("{path}: http {status}"));
+    }
+    let mut body = raw[split + 4..].to_vec();
+    if head.contains("transfer-encoding: chunked") {
+        body = decode_chunked(&body);
+    }
+    serde_json::from_slice(&body).map_err(|e| format!("{path}: {e}"))
+}
+
+fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(pos) = data.windows(2).position(|w| w == b"\r\n") {
+        let size_str = String::from_utf8_lossy(&data[..pos]);
+        let size_hex = size_str.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).unwrap_or(0);
+        if size == 0 || data.len() < pos + 2 + size {
+            break;
+        }
+        out.extend_from_slice(&data[pos + 2..pos + 2 + size]);
+        data = &data[(pos + 4 + size).min(data.len())..];
+    }
+    out
+}
+
+/// 逐容器的详情与统计请求并发执行，单个容器卡住最多拖慢一个 DOCKER_TIMEOUT。
+fn read_containers(
+    socket: &str,
+    prev_cpu: &mut HashMap<String, (u64, u64)>,
+) -> Result<Vec<Value>, String> {
+    let list = match unix_http_get(socket, "/containers/json?all=1")? {
+        Value::Array(list) => list,
+        _ => return Err("unexpected /containers/json response".into()),
+    };
+    let items: Vec<(String, &Value)> = list
+        .iter()
+        .filter_map(|item| Some((item.get("Id")?.as_str()?.to_string(), item)))
+        .collect();
+    // (RestartCount, 运行中容器的 one-shot 统计)
+    let details: Vec<Mutex<(u64, Option<Value>)>> =
+        items.iter().map(|_| Mutex::new((0, None))).collect();
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..DOCKER_WORKERS.min(items.len()) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some((id, item)) = items.get(idx) else { break };
+                let restart_count = unix_http_get(socket, &format!("/containers/{id}/json"))
+                    .ok()
+                    .and_then(|v| v.get("RestartCount").and_then(Value::as_u64))
+                    .unwrap_or(0);
+                let running = item.get("State").and_then(Value::as_str) == Some("running");
+                let stats_path = format!("/containers/{id}/stats?stream=false&one-shot=true");
+                let stats = running.then(|| unix_http_get(socket, &stats_path).ok()).flatten();
+                *details[idx].lock().unwrap() = (restart_count, stats);
+            });
+        }
+    });
+    let mut result = Vec::with_capacity(items.len());
+    for ((id, item), detail) in items.iter().zip(details) {
+        let (restart_count, stats) = detail.into_inner().unwrap();
+        let name = item
+            .get("Names")
+            .and_then(|n| n.get(0))
+            .and_then(Value::as_str)
+            .unwrap_or(id)
+            .trim_start_matches('/')
+            .to_string();
+        let mut entry = json!({
+            "id": id.chars().take(12).collect::<String>(),
+            "name": name,
+            "image": item.get("Image").and_then(Value::as_str).unwrap_or(""),
+            "state": item.get("State").and_then(Value::as_str).unwrap_or("unknown"),
+            "status": item.get("Status").and_then(Value::as_str).unwrap_or(""),
+            "restart_count": restart_count,
+        });
+        if let Some(stats) = stats {
+            apply_container_stats(&mut entry, &stats, prev_cpu.get(id).copied());
+            if let Some(sample) = container_cpu_sample(&stats) {
+                prev_cpu.insert(id.clone(), sample);
+            }
+        }
+        result.push(entry);
+    }
+    prev_cpu.retain(|id, _| items.iter().any(|(seen, _)| seen == id));
+    Ok(result)
+}
+
+/// 返回 (容器累计 CPU 纳秒, 系统累计 CPU 纳秒)。one-shot 模式下 precpu_stats 为空，需自行保存上一轮。
+fn container_cpu_sample(stats: &Value) -> Option<(u64, u64)> {
+    let cpu = stats.get("cpu_stats")?;
+    Some((
+        cpu.get("cpu_usage")?.get("total_usage")?.as_u64()?,
+        cpu.get("system_cpu_usage")?.as_u64()?,
+    ))
+}
+
+fn apply_container_stats(entry: &mut Value, stats: &Value, prev: Option<(u64, u64)>) {
+    let online_cpus = stats
+        .pointer("/cpu_stats/online_cpus")
+        .and_then(Value::as_u64)
+        .unwrap_or(1)
+        .max(1);
+    let cpu = match (prev, container_cpu_sample(stats)) {
+        (Some((pc, ps)), Some((cc, cs))) if cs > ps => {
+            cc.saturating_sub(pc) as f64 / (cs - ps) as f64 * online_cpus as f64 * 100.0
+        }
+        _ => 0.0,
+    };
+    // 与 docker stats 一致：扣除可回收的文件缓存（cgroup v2 为 inactive_file，v1 为 cache）
+    let mem = stats.get("memory_stats");
+    let usage = mem.and_then(|m| m.get("usage")).and_then(Value::as_u64).unwrap_or(0);
+    let cache = mem
+        .and_then(|m| m.get("stats"))
+        .and_then(|s| s.get("inactive_file").or_else(|| s.get("cache")))
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let limit = mem.and_then(|m| m.get("limit")).and_then(Value::as_u64).unwrap_or(0);
+    let (mut rx, mut tx) = (0u64, 0u64);
+    if let Some(nets) = stats.get("networks").and_then(Value::as_object) {
+        for net in nets.values() {
+            rx += net.get("rx_bytes").and_then(Value::as_u64).unwrap_or(0);
+            tx += net.get("tx_bytes").and_then(Value::as_u64).unwrap_or(0);
+        }
+    }
+    if let Some(obj) = entry.as_object_mut() {
+        obj.insert("cpu".into(), json!(round2(cpu)));
+        obj.insert("memory_usage".into(), json!(usage.saturating_sub(cache)));
+        obj.insert("memory_limit".into(), json!(limit));
+        obj.insert("net_rx".into(), json!(rx));
+        obj.insert("net_tx".into(), json!(tx));
+    }
+}
+
+/// 返回单元的 (ActiveState, SubState, NRestarts)。LoadUnit 对未加载的单元也会返回路径，
+/// 不存在的单元 ActiveState 为 inactive。
+fn systemd_unit_state(
+    bus: &mut Option<zbus::blocking::Connection>,
+    kind: &str,
+    unit: &str,
+) -> (String, String, u64) {
+    let unknown = || ("unknown".to_string(), "unknown".to_string(), 0);
+    let Some(conn) = systemd_connection(bus, kind) else {
+        return unknown();
+    };
+    let reply = conn.call_method(
+        Some("org.freedesktop.systemd1"),
+        "/org/freedesktop/systemd1",
+        Some("org.freedesktop.systemd1.Manager"),
+        "LoadUnit",
+        &(unit,),
+    );
+    let path: OwnedObjectPath = match reply.and_then(|msg| msg.body().deserialize()) {
+        Ok(path) => path,
+        // 单元名非法等方法级错误不影响连接本身，其余错误下一轮重连
+        Err(zbus::Error::MethodError(..)) => return unknown(),
+        Err(_) => {
+            *bus = None;
+            return unknown();
+        }
+    };
+    let text = |prop: &str| {
+        unit_property(conn, &path, "org.freedesktop.systemd1.Unit", prop)
+            .and_then(|v| String::try_from(v).ok())
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    // NRestarts 只存在于 service 单元
+    let restarts = unit_property(conn, &path, "org.freedesktop.systemd1.Service", "NRestarts")
+        .and_then(|v| u32::try_from(v).ok())
+        .unwrap_or(0);
+    (text("ActiveState"), text("SubState"), restarts as u64)
+}
+
+fn read_net_dev(path: &str, include: &[String], exclude: &[String]) -> Vec<NetIface> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    let mut result = Vec::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok).skip(2) {
+        if let Some((iface, data)) = line.split_once(':') {
+            let iface = iface.trim();
+            if iface.is_empty() || !iface_selected(iface, include, exclude) {
+                continue;
+            }
+            let fields: Vec<u64> = data
+                .split_whitespace()
+                .map(|f| f.parse().unwrap_or(0))
+                .collect();
+            if fields.len() < 12 {
+                continue;
+            }
+            result.push(NetIface {
+                name: iface.to_string(),
+                rx_bytes: fields[0],
+                rx_packets: fields[1],
+                rx_errors: fields[2],
+                rx_drops: fields[3],
+                tx_bytes: fields[8],
+                tx_packets: fields[9],
+                tx_errors: fields[10],
+                tx_drops: fields[11],
+            });
+        }
+    }
+    result
+}
+
+fn iface_selected(name: &str, include: &[String], exclude: &[String]) -> bool {
+    if !include.is_empty() && !include.iter().any(|p| glob_match(p, name)) {
+        return false;
+    }
+    !exclude.iter().any(|p| glob_match(p, name))
+}
+
+/// 仅支持 `*` 与 `?` 通配，足够覆盖网卡/设备名过滤。
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    while pi < p.len() && p[pi] == '*' {
+        pi += 1;
+    }
+    pi == p.len()
+}
+
+fn read_sys_value(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok().map(|v| v.trim().to_string())
+}
+
+fn iface_to_json(iface: &NetIface, tx_speed: f64, rx_speed: f64) -> Value {
+    let base = format!("/sys/class/net/{}", iface.name);
+    let operstate = read_sys_value(&format!("{base}/operstate")).unwrap_or_else(|| "unknown".into());
+    // 虚拟网卡或未连接时 speed 读取失败或为 -1
+    let speed = read_sys_value(&format!("{base}/speed"))
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0);
+    json!({
+        "name": iface.name,
+        "operstate": operstate,
+        "speed_mbps": speed,
+        "rx_bytes": iface.rx_bytes,
+        "tx_bytes": iface.tx_bytes,
+        "rx_packets": iface.rx_packets,
+        "tx_packets": iface.tx_packets,
+        "rx_errors": iface.rx_errors,
+        "tx_errors": iface.tx_errors,
+        "rx_drops": iface.rx_drops,
+        "tx_drops": iface.tx_drops,
+        "rx_speed": round3(rx_speed),
+        "tx_speed": round3(tx_speed),
+    })
+}
+
+fn read_loadavg() -> Option<[f64; 3]> {
+    let mut line = String::new();
+    let mut file = File::open("/proc/loadavg").ok()?;
+    use std::io::Read;
+    file.read_to_string(&mut line).ok()?;
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 3 {
+        return None;
+    }
+    let one = parts[0].parse::<f64>().ok()?;
+    let five = parts[1].parse::<f64>().ok()?;
+    let fifteen = parts[2].parse::<f64>().ok()?;
+    Some([one, five, fifteen])
+}
+
+fn detect_container_runtime() -> Option<String> {
+    if Path::new("/.dockerenv").exists() {
+        return Some("docker".into());
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some("podman".into());
+    }
+    // systemd-nspawn / LXC 会给 1 号进程设置 container= 环境变量
+    if let Ok(raw) = std::fs::read("/proc/1/environ") {
+        for var in raw.split(|b| *b == 0) {
+            if let Some(val) = var.strip_prefix(b"container=") {
+                let val = String::from_utf8_lossy(val).trim().to_string();
+                if !val.is_empty() {
+                    return Some(val);
+                }
+            }
+        }
+    }
+    let cgroup = std::fs::read_to_string("/proc/1/cgroup").unwrap_or_default();
+    for (needle, name) in [("kubepods", "kubernetes"), ("docker", "docker"), ("containerd", "containerd"), ("lxc", "lxc")] {
+        if cgroup.contains(needle) {
+            return Some(name.into());
+        }
+    }
+    None
+}
+
+/// 读取容器根 cgroup 的限额与用量，自动区分 v1 与 v2。
+///
+/// Agent 自身可能运行在容器内某个 systemd 服务的子 cgroup 中，因此按 1 号进程
+/// （`proc_cgroup` 通常为 /proc/1/cgroup）所在的 cgroup 定位容器根。
+fn read_cgroup(root: &str, proc_cgroup: &str) -> Option<CgroupSample> {
+    let init_cgroup = std::fs::read_to_string(proc_cgroup).ok()?;
+    if Path::new(&format!("{root}/cgroup.controllers")).exists() {
+        let rel = init_cgroup
+            .lines()
+            .find_map(|l| l.strip_prefix("0::"))
+            .unwrap_or("/");
+        let dir = cgroup_dir(root, container_cgroup_path(rel));
+        let read = |f: &str| read_sys_value(&format!("{dir}/{f}"));
+        let num = |f: &str| read(f).and_then(|v| v.parse::<u64>().ok());
+        let stat = read_kv_file(&format!("{dir}/cpu.stat"));
+        let mem_stat = read_kv_file(&format!("{dir}/memory.stat"));
+        let cpu_quota = read("cpu.max").and_then(|v| {
+            let mut parts = v.split_whitespace();
+            let quota = parts.next()?.parse::<f64>().ok()?;
+            let period = parts.next()?.parse::<f64>().ok()?;
+            (period > 0.0).then(|| quota / period)
+        });
+        return Some(CgroupSample {
+            version: 2,
+            mem_usage: num("memory.current").unwrap_or(0),
+            mem_limit: num("memory.max"),
+            mem_inactive_file: mem_stat.get("inactive_file").copied().unwrap_or(0),
+            cpu_quota,
+            cpu_usage_usec: stat.get("usage_usec").copied(),
+            nr_periods: stat.get("nr_periods").copied().unwrap_or(0),
+            nr_throttled: stat.get("nr_throttled").copied().unwrap_or(0),
+            throttled_usec: stat.get("throttled_usec").copied().unwrap_or(0),
+            pids_current: num("pids.current"),
+            pids_max: num("pids.max"),
+        });
+    }
+    // v1：每个控制器单独挂载，形如 "4:memory:/docker/<id>"
+    let controller_dir = |name: &str| {
+        let rel = init_cgroup.lines().find_map(|l| {
+            let mut parts = l.splitn(3, ':');
+            let (_, ctrls, rel) = (parts.next()?, parts.next()?, parts.next()?);
+            ctrls.split(',').any(|c| c == name).then_some(rel)
+        });
+        rel.map(|rel| cgroup_dir(&format!("{root}/{name}"), container_cgroup_path(rel)))
+    };
+    let mem_dir = controller_dir("memory")?;
+    let cpu_dir = controller_dir("cpu").unwrap_or_else(|| format!("{root}/cpu"));
+    let acct_dir = controller_dir("cpuacct").unwrap_or_else(|| format!("{root}/cpuacct"));
+    let pids_dir = controller_dir("pids").unwrap_or_else(|| format!("{root}/pids"));
+    let num = |path: String| read_sys_value(&path).and_then(|v| v.parse::<i64>().ok());
+    let mem_stat = read_kv_file(&format!("{mem_dir}/memory.stat"));
+    let cpu_stat = read_kv_file(&format!("{cpu_dir}/cpu.stat"));
+    let quota = num(format!("{cpu_dir}/cpu.cfs_quota_us")).unwrap_or(-1);
+    let period = num(format!("{cpu_dir}/cpu.cfs_period_us")).unwrap_or(0);
+    Some(CgroupSample {
+        version: 1,
+        mem_usage: num(format!("{mem_dir}/memory.usage_in_bytes")).unwrap_or(0) as u64,
+        // 未设置限额时内核返回接近 i64::MAX 的值
+        mem_limit: num(format!("{mem_dir}/memory.limit_in_bytes"))
+            .filter(|v| *v > 0 && *v < 1 << 62)
+            .map(|v| v as u64),
+        mem_inactive_file: mem_stat
+            .get("total_inactive_file")
+            .or_else(|| mem_stat.get("inactive_file"))
+            .copied()
+            .unwrap_or(0),
+        cpu_quota: (quota > 0 && period > 0).then(|| quota as f64 / period as f64),
+        cpu_usage_usec: num(format!("{acct_dir}/cpuacct.usage")).map(|ns| ns as u64 / 1000),
+        nr_periods: cpu_stat.get("nr_periods").copied().unwrap_or(0),
+        nr_throttled: cpu_stat.get("nr_throttled").copied().unwrap_or(0),
+        throttled_usec: cpu_stat.get("throttled_time").copied().unwrap_or(0) / 1000,
+        pids_current: num(format!("{pids_dir}/pids.current")).map(|v| v as u64),
+        pids_max: num(format!("{pids_dir}/pids.max")).map(|v| v as u64),
+    })
+}
+
+/// 容器内的 systemd 会把自身移入 init.scope，限额设在其上一级的容器根上。
+fn container_cgroup_path(rel: &str) -> &str {
+    let rel = rel.trim_end_matches('/');
+    rel.strip_suffix("/init.scope").unwrap_or(rel)
+}
+
+/// 开启 cgroup namespace 时 /proc/1/cgroup 中的路径在挂载点下不存在，回退到挂载点根目录。
+fn cgroup_dir(mount: &str, rel: &str) -> String {
+    let candidate = format!("{mount}{}", rel.trim_end_matches('/'));
+    if Path::new(&candidate).is_dir() {
+        candidate
+    } else {
+        mount.to_string()
+    }
+}
+
+fn read_kv_file(path: &str) -> HashMap<String, u64> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| {
+            let (k, v) = l.split_once(' ')?;
+            Some((k.to_string(), v.trim().parse::<u64>().ok()?))
+        })
+        .collect()
+}
+
+/// 返回上报用的 JSON，以及按配额（无配额时按核数）折算的 CPU 使用率。
+/// `prev` 为上一次的样本及距今实际经过的秒数。
+fn cgroup_to_json(cg: &CgroupSample, prev: Option<(&CgroupSample, f64)>, host_cores: u64) -> (Value, Option<f64>) {
+    let cores = cg.cpu_quota.unwrap_or(host_cores.max(1) as f64);
+    let cpu_percent = match (prev, cg.cpu_usage_usec) {
+        (Some((p, secs)), Some(c)) => p.cpu_usage_usec.map(|p| {
+            let used = c.saturating_sub(p) as f64;
+            (used / (secs.max(0.001) * 1_000_000.0 * cores) * 100.0).min(100.0)
+        }),
+        _ => None,
+    };
+    let throttled_percent = prev.map(|(p, _)| {
+        percent(
+            cg.nr_throttled.saturating_sub(p.nr_throttled),
+            cg.nr_periods.saturating_sub(p.nr_periods),
+        )
+    });
+    let json = json!({
+        "version": cg.version,
+        "memory_usage": cg.mem_usage,
+        "memory_limit": cg.mem_limit,
+        "memory_percent": cg.mem_limit.map(|l| round2(percent(cg.mem_usage.saturating_sub(cg.mem_inactive_file), l))),
+        "cpu_quota_cores": cg.cpu_quota.map(round2),
+        "cpu_percent": cpu_percent.map(round2),
+        "nr_periods": cg.nr_periods,
+        "nr_throttled": cg.nr_throttled,
+        "throttled_usec": cg.throttled_usec,
+        "throttled_percent": throttled_percent.map(round2),
+        "pids_current": cg.pids_current,
+        "pids_max": cg.pids_max,
+    });
+    (json, cpu_percent)
+}
+
+fn read_pressure(dir: &str) -> Option<Value> {
+    let mut result = Map::new();
+    for resource in ["cpu", "memory", "io"] {
+        let content = match std::fs::read_to_string(format!("{dir}/{resource}")) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let mut kinds = Map::new();
+        for line in content.lines() {
+            // some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+            let mut parts = line.split_whitespace();
+            let kind = match parts.next() {
+                Some(k @ ("some" | "full")) => k,
+                _ => continue,
+            };
+            let mut values = Map::new();
+            for part in parts {
+                if let Some((key, val)) = part.split_once('=') {
+                    if key.starts_with("avg") {
+                        if let Ok(v) = val.parse::<f64>() {
+                            values.insert(key.into(), json!(v));
+                        }
+                    }
+                }
+            }
+            kinds.insert(kind.into(), Value::Object(values));
+        }
+        if !kinds.is_empty() {
+            result.insert(resource.into(), Value::Object(kinds));
+        }
+    }
+    if result.is_empty() {
+        None
+    } else {
+        Some(Value::Object(result))
+    }
+}
+
+const TCP_STATES: [&str; 12] = [
+    "ESTABLISHED",
+    "SYN_SENT",
+    "SYN_RECV",
+    "FIN_WAIT1",
+    "FIN_WAIT2",
+    "TIME_WAIT",
+    "CLOSE",
+    "CLOSE_WAIT",
+    "LAST_ACK",
+    "LISTEN",
+    "CLOSING",
+    "NEW_SYN_RECV",
+];
+const TCP_LISTEN: u8 = 0x0A;
+// UDP 未 connect 的套接字状态为 TCP_CLOSE
+const UDP_UNCONNECTED: u8 = 0x07;
+
+/// 解析 /proc/net/{tcp,tcp6,udp,udp6} 格式的套接字表。
+fn read_socket_table(path: &str) -> Vec<SocketEntry> {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            //   0: 0100007F:BC8F 00000000:0000 0A ... uid timeout inode
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let (local_hex, port_hex) = fields[1].split_once(':')?;
+            let (_, remote_port_hex) = fields[2].split_once(':')?;
+            Some(SocketEntry {
+                local: decode_proc_addr(local_hex)?,
+                port: u16::from_str_radix(port_hex, 16).ok()?,
+                remote_port: u16::from_str_radix(remote_port_hex, 16).ok()?,
+                state: u8::from_str_radix(fields[3], 16).ok()?,
+                inode: fields[9].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// 地址按 32 位字以主机字节序（小端）输出。
+fn decode_proc_addr(hex: &str) -> Option<String> {
+    let words = (0..hex.len() / 8)
+        .map(|i| u32::from_str_radix(&hex[i * 8..i * 8 + 8], 16).map(u32::swap_bytes))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    match words.as_slice() {
+        [v4] => Some(std::net::Ipv4Addr::from(*v4).to_string()),
+        [a, b, c, d] => {
+            let mut octets = [0u8; 16];
+            for (i, w) in [a, b, c, d].into_iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&w.to_be_bytes());
+            }
+            Some(std::net::Ipv6Addr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// 内核分配临时端口的范围；读取失败时使用内核默认值。
+fn read_local_port_range(proc_root: &str) -> (u16, u16) {
+    let path = format!("{proc_root}/sys/net/ipv4/ip_local_port_range");
+    let raw = std::fs::read_to_string(path).unwrap_or_default();
+    let mut parts = raw.split_whitespace().map(|p| p.parse::<u16>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(low)), Some(Ok(high))) if low <= high => (low, high),
+        _ => (32768, 60999),
+    }
+}
+
+/// 列出监听中的 TCP 与未连接的 UDP 套接字，并通过 /proc/[pid]/fd 的 inode 找到所属进程。
+///
+/// 客户端发 UDP 请求（如 DNS 查询）时绑定的临时端口同样处于未连接状态，
+/// 因此落在 ip_local_port_range 内的 UDP 端口不算监听。
+fn read_listening(proc_root: &str, procs: &[ProcSample]) -> Vec<Value> {
+    let (ephemeral_low, ephemeral_high) = read_local_port_range(proc_root);
+    let mut sockets = Vec::new();
+    for (file, proto, state) in [
+        ("tcp", "tcp", TCP_LISTEN),
+        ("tcp6", "tcp", TCP_LISTEN),
+        ("udp", "udp", UDP_UNCONNECTED),
+        ("udp6", "udp", UDP_UNCONNECTED),
+    ] {
+        for sock in read_socket_table(&format!("{proc_root}/net/{file}")) {
+            let ephemeral = proto == "udp" && (ephemeral_low..=ephemeral_high).contains(&sock.port);
+            if sock.state == state && sock.remote_port == 0 && !ephemeral {
+                sockets.push((proto, sock));
+            }
+        }
+    }
+    // SO_REUSEPORT 下同一地址端口可能有多个套接字，只保留一条
+    sockets.sort_by(|(pa, a), (pb, b)| (pa, a.port, &a.local).cmp(&(pb, b.port, &b.local)));
+    sockets.dedup_by(|(pa, a), (pb, b)| pa == pb && a.port == b.port && a.local == b.local);
+    let wanted: HashSet<u64> = sockets.iter().map(|(_, s)| s.inode).collect();
+    let mut owners: HashMap<u64, u32> = HashMap::new();
+    for proc in procs {
+        if owners.len() == wanted.len() {
+            break;
+        }
+        // 非 root 运行时读不到其他用户进程的 fd，保持未知
+        let Ok(fds) = std::fs::read_dir(format!("{proc_root}/{}/fd", proc.pid)) else {
+            continue;
+        };
+        for fd in fds.filter_map(|e| e.ok()) {
+            let Ok(link) = std::fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = link
+                .to_str()
+                .and_then(|l| l.strip_prefix("socket:["))
+                .and_then(|l| l.strip_suffix(']'))
+                .and_then(|l| l.parse::<u64>().ok());
+            if let Some(inode) = inode.filter(|i| wanted.contains(i)) {
+                owners.entry(inode).or_insert(proc.pid);
+            }
+        }
+    }
+    sockets
+        .into_iter()
+        .map(|(proto, sock)| {
+            let pid = owners.get(&sock.inode).copied();
+            let process = pid.and_then(|pid| procs.iter().find(|p| p.pid == pid)).map(|p| p.name.clone());
+            json!({
+                "proto": proto,
+                "address": sock.local,
+                "port": sock.port,
+                "pid": pid,
+                "process": process,
+            })
+        })
+        .collect()
+}
+
+fn read_socket_summary(proc_root: &str) -> Value {
+    let mut states = Map::new();
+    let mut tcp_total = 0u64;
+    let mut listen_tcp = Vec::new();
+    for file in ["tcp", "tcp6"] {
+        for sock in read_socket_table(&format!("{proc_root}/net/{file}")) {
+            tcp_total += 1;
+            let name = TCP_STATES
+                .get((sock.state as usize).wrapping_sub(1))
+                .copied()
+                .unwrap_or("UNKNOWN");
+            let count = states.get(name).and_then(Value::as_u64).unwrap_or(0);
+            states.insert(name.into(), json!(count + 1));
+            if sock.state == TCP_LISTEN {
+                listen_tcp.push(sock.port);
+            }
+        }
+    }
+    let mut udp_total = 0u64;
+    let mut listen_udp = Vec::new();
+    for file in ["udp", "udp6"] {
+        for sock in read_socket_table(&format!("{proc_root}/net/{file}")) {
+            udp_total += 1;
+            if sock.state == UDP_UNCONNECTED && sock.remote_port == 0 {
+                listen_udp.push(sock.port);
+            }
+        }
+    }
+    for ports in [&mut listen_tcp, &mut listen_udp] {
+        ports.sort_unstable();
+        ports.dedup();
+    }
+    json!({
+        "tcp": states,
+        "tcp_total": tcp_total,
+        "udp_total": udp_total,
+        "listen_tcp": listen_tcp,
+        "listen_udp": listen_udp,
+        "sockstat": read_sockstat(&format!("{proc_root}/net/sockstat")),
+    })
+}
+
+/// sockstat 的每行形如 `TCP: inuse 4 orphan 0 tw 6 alloc 4 mem 0`，展开为 `tcp_inuse` 等键。
+fn read_sockstat(path: &str) -> Value {
+    let mut result = Map::new();
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    for line in content.lines() {
+        let Some((proto, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        for pair in fields.chunks(2) {
+            if let [key, val] = pair {
+                if let Ok(v) = val.parse::<u64>() {
+                    result.insert(format!("{}_{}", proto.to_lowercase(), key), json!(v));
+                }
+            }
+        }
+    }
+    Value::Object(result)
+}
+
+fn read_conntrack(dir: &str) -> Option<Value> {
+    let count = read_sys_value(&format!("{dir}/nf_conntrack_count"))?.parse::<u64>().ok()?;
+    let max = read_sys_value(&format!("{dir}/nf_conntrack_max"))
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    Some(json!({
+        "count": count,
+        "max": max,
+        "percent": round2(percent(count, max)),
+    }))
+}
+
+/// `sys_root` 通常为 `/sys`，便于指向伪造的 sysfs 目录调试。
+fn read_temperatures(sys_root: &str) -> Vec<TempSensor> {
+    let mut sensors = Vec::new();
+    for dir in sorted_dir_entries(&format!("{sys_root}/class/hwmon"), "hwmon") {
+        // 旧内核把传感器文件放在 device/ 子目录下
+        let base = if Path::new(&format!("{dir}/name")).exists() {
+            dir
+        } else {
+            format!("{dir}/device")
+        };
+        let chip = read_sys_value(&format!("{base}/name")).unwrap_or_else(|| "hwmon".into());
+        let mut inputs: Vec<String> = match std::fs::read_dir(&base) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .filter(|n| n.starts_with("temp") && n.ends_with("_input"))
+                .collect(),
+            Err(_) => continue,
+        };
+        inputs.sort_by_key(|n| {
+            n.trim_start_matches("temp")
+                .trim_end_matches("_input")
+                .parse::<u32>()
+                .unwrap_or(0)
+        });
+        for input in inputs {
+            let prefix = input.trim_end_matches("_input");
+            let celsius = match read_millidegrees(&format!("{base}/{input}")) {
+                Some(v) => v,
+                None => continue,
+            };
+            let label = read_sys_value(&format!("{base}/{prefix}_label"))
+                .unwrap_or_else(|| prefix.to_string());
+            sensors.push(TempSensor {
+                sensor: format!("{chip}/{label}"),
+                source: "hwmon",
+                celsius,
+                high: read_millidegrees(&format!("{base}/{prefix}_max")),
+                critical: read_millidegrees(&format!("{base}/{prefix}_crit")),
+            });
+        }
+    }
+    for dir in sorted_dir_entries(&format!("{sys_root}/class/thermal"), "thermal_zone") {
+        let celsius = match read_millidegrees(&format!("{dir}/temp")) {
+            Some(v) => v,
+            None => continue,
+        };
+        let zone = dir.rsplit('/').next().unwrap_or("thermal_zone").to_string();
+        let kind = read_sys_value(&format!("{dir}/type")).unwrap_or_else(|| zone.clone());
+        let mut critical = None;
+        let mut high = None;
+        for idx in 0.. {
+            let trip_type = match read_sys_value(&format!("{dir}/trip_point_{idx}_type")) {
+                Some(t) => t,
+                None => break,
+            };
+            let trip_temp = read_millidegrees(&format!("{dir}/trip_point_{idx}_temp"));
+            match trip_type.as_str() {
+                "critical" => critical = trip_temp,
+                "hot" => high = trip_temp,
+                _ => {}
+            }
+        }
+        sensors.push(TempSensor {
+            sensor: format!("{zone}/{kind}"),
+            source: "thermal",
+            celsius,
+            high,
+            critical,
+        });
+    }
+    sensors
+}
+
+fn sorted_dir_entries(dir: &str, prefix: &str) -> Vec<String> {
+    let mut entries: Vec<String> = match std::fs::read_dir(dir) {
+        Ok(e) => e
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with(prefix))
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    entries.sort_by_key(|n| n.trim_start_matches(prefix).parse::<u32>().unwrap_or(0));
+    entries.into_iter().map(|n| format!("{dir}/{n}")).collect()
+}
+
+fn read_millidegrees(path: &str) -> Option<f64> {
+    let raw = read_sys_value(path)?.parse::<i64>().ok()?;
+    // 部分驱动在无读数时返回 -273200 之类的哨兵值
+    if raw <= -273000 {
+        return None;
+    }
+    Some(raw as f64 / 1000.0)
+}
+
+fn temp_to_json(t: &TempSensor) -> Value {
+    json!({
+        "sensor": t.sensor,
+        "source": t.source,
+        "celsius": round2(t.celsius),
+        "high": t.high.map(round2),
+        "critical": t.critical.map(round2),
+    })
+}
+
+fn read_uptime() -> Option<u64> {
+    let mut line = String::new();
+    let mut file = File::open("/proc/uptime").ok()?;
+    use std::io::Read;
+    file.read_to_string(&mut line).ok()?;
+    let first = line.split_whitespace().next()?;
+    first.parse::<f64>().ok().map(|v| v as u64)
+}
+
+fn read_os_info() -> (String, String) {
+    let mut pretty = String::new();
+    let mut name = String::new();
+    let mut version = String::new();
+    if let Ok(file) = File::open("/etc/os-release") {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            if line.starts_with("PRETTY_NAME=") {
+                pretty = line.trim_start_matches("PRETTY_NAME=").trim_matches('"').to_string();
+            } else if line.starts_with("NAME=") && name.is_empty() {
+                name = line.trim_start_matches("NAME=").trim_matches('"').to_string();
+            } else if line.starts_with("VERSION=") && version.is_empty() {
+                version = line.trim_start_matches("VERSION=").trim_matches('"').to_string();
+            }
+        }
+    }
+    let os_short = if !pretty.is_empty() {
         pretty.clone()
     } else if !name.is_empty() {
         if !version.is_empty() {
@@ -422,7 +3720,7 @@ fn read_cpu_info() -> CpuInfo {
         let mut hypervisor = false;
         let mut hyper_vendor = String::new();
         let mut raw_model = String::new();
-        for line in BufReader::new(file).lines().flatten() {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
             if line.starts_with("model name") && model == "Unknown CPU" {
                 if let Some(val) = line.split(':').nth(1) {
                     let trimmed = val.trim().to_string();
@@ -470,11 +3768,11 @@ fn read_cpu_info() -> CpuInfo {
     }
 }
 
-fn bytes_per_sec_to_mb(bytes: u64, interval_sec: u64) -> f64 {
-    if interval_sec == 0 {
+fn bytes_per_sec_to_mb(bytes: u64, elapsed_sec: f64) -> f64 {
+    if elapsed_sec <= 0.0 {
         return 0.0;
     }
-    let per_sec = bytes as f64 / interval_sec as f64;
+    let per_sec = bytes as f64 / elapsed_sec;
     per_sec / (1024.0 * 1024.0)
 }
 
@@ -482,6 +3780,14 @@ fn bytes_to_gb(bytes: u64) -> f64 {
     bytes as f64 / (1024.0 * 1024.0 * 1024.0)
 }
 
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64 * 100.0
+    }
+}
+
 fn round2(value: f64) -> f64 {
     (value * 100.0).round() / 100.0
 }
@@ -489,3 +3795,1004 @@ fn round2(value: f64) -> f64 {
 fn round3(value: f64) -> f64 {
     (value * 1000.0).round() / 1000.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 在临时目录下按 (相对路径, 内容) 生成一棵文件树，返回根目录。
+    fn fixture(name: &str, files: &[(&str, &str)]) -> String {
+        let root = env::temp_dir().join(format!("imonitor-agent-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn cgroup_v2_uses_container_root_limits() {
+        let root = fixture(
+            "cgroup-v2",
+            &[
+                ("proc1", "0::/lxc/101/init.scope\n"),
+                ("sys/cgroup.controllers", "cpu memory pids\n"),
+                ("sys/lxc/101/cpu.max", "150000 100000\n"),
+                ("sys/lxc/101/memory.max", "1073741824\n"),
+                ("sys/lxc/101/memory.current", "536870912\n"),
+                ("sys/lxc/101/memory.stat", "anon 1\ninactive_file 1024\n"),
+                ("sys/lxc/101/cpu.stat", "usage_usec 5000\nnr_periods 10\nnr_throttled 2\n"),
+                ("sys/lxc/101/pids.current", "12\n"),
+                ("sys/lxc/101/pids.max", "max\n"),
+                // agent 自身所在的服务 cgroup 没有限额，不应被读取
+                ("sys/lxc/101/system.slice/imonitor.service/memory.max", "max\n"),
+            ],
+        );
+        let cg = read_cgroup(&format!("{root}/sys"), &format!("{root}/proc1")).unwrap();
+        assert_eq!(cg.version, 2);
+        assert_eq!(cg.cpu_quota, Some(1.5));
+        assert_eq!(cg.mem_limit, Some(1 << 30));
+        assert_eq!(cg.mem_usage, 1 << 29);
+        assert_eq!(cg.mem_inactive_file, 1024);
+        assert_eq!(cg.cpu_usage_usec, Some(5000));
+        assert_eq!(cg.nr_throttled, 2);
+        assert_eq!(cg.pids_current, Some(12));
+        assert_eq!(cg.pids_max, None);
+    }
+
+    #[test]
+    fn cgroup_v2_max_means_no_limit() {
+        // cgroup namespace 内 1 号进程看到的是 0::/
+        let root = fixture(
+            "cgroup-v2-max",
+            &[
+                ("proc1", "0::/\n"),
+                ("sys/cgroup.controllers", "cpu memory\n"),
+                ("sys/cpu.max", "max 100000\n"),
+                ("sys/memory.max", "max\n"),
+                ("sys/memory.current", "4096\n"),
+            ],
+        );
+        let cg = read_cgroup(&format!("{root}/sys"), &format!("{root}/proc1")).unwrap();
+        assert_eq!(cg.cpu_quota, None);
+        assert_eq!(cg.mem_limit, None);
+        assert_eq!(cg.mem_usage, 4096);
+    }
+
+    #[test]
+    fn cgroup_v1_reads_per_controller_dirs() {
+        let root = fixture(
+            "cgroup-v1",
+            &[
+                ("proc1", "5:memory:/docker/abc\n4:cpu,cpuacct:/docker/abc\n3:pids:/docker/abc\n"),
+                ("sys/memory/docker/abc/memory.usage_in_bytes", "2048\n"),
+                ("sys/memory/docker/abc/memory.limit_in_bytes", "1048576\n"),
+                ("sys/memory/docker/abc/memory.stat", "total_inactive_file 512\n"),
+                ("sys/cpu/docker/abc/cpu.cfs_quota_us", "50000\n"),
+                ("sys/cpu/docker/abc/cpu.cfs_period_us", "100000\n"),
+                ("sys/cpu/docker/abc/cpu.stat", "nr_periods 4\nnr_throttled 1\nthrottled_time 3000\n"),
+                ("sys/cpuacct/docker/abc/cpuacct.usage", "7000000\n"),
+                ("sys/pids/docker/abc/pids.current", "3\n"),
+            ],
+        );
+        let cg = read_cgroup(&format!("{root}/sys"), &format!("{root}/proc1")).unwrap();
+        assert_eq!(cg.version, 1);
+        assert_eq!(cg.mem_usage, 2048);
+        assert_eq!(cg.mem_limit, Some(1048576));
+        assert_eq!(cg.mem_inactive_file, 512);
+        assert_eq!(cg.cpu_quota, Some(0.5));
+        assert_eq!(cg.cpu_usage_usec, Some(7000));
+        assert_eq!(cg.throttled_usec, 3);
+        assert_eq!(cg.pids_current, Some(3));
+    }
+
+    #[test]
+    fn cgroup_v1_without_quota() {
+        let root = fixture(
+            "cgroup-v1-unlimited",
+            &[
+                ("proc1", "5:memory:/\n4:cpu,cpuacct:/\n"),
+                ("sys/memory/memory.usage_in_bytes", "2048\n"),
+                ("sys/memory/memory.limit_in_bytes", "9223372036854771712\n"),
+                ("sys/cpu/cpu.cfs_quota_us", "-1\n"),
+                ("sys/cpu/cpu.cfs_period_us", "100000\n"),
+            ],
+        );
+        let cg = read_cgroup(&format!("{root}/sys"), &format!("{root}/proc1")).unwrap();
+        assert_eq!(cg.mem_limit, None);
+        assert_eq!(cg.cpu_quota, None);
+    }
+
+    /// 单连接的假 Docker 服务：读完请求头后回写固定响应，返回收到的请求行。
+    fn stub_http_server(name: &str, response: &'static [u8]) -> (String, thread::JoinHandle<String>) {
+        let path = env::temp_dir().join(format!("imonitor-agent-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            reader.get_mut().write_all(response).unwrap();
+            request_line
+        });
+        (path.to_string_lossy().into_owned(), handle)
+    }
+
+    #[test]
+    fn unix_http_get_decodes_chunked_json() {
+        let (socket, server) = stub_http_server(
+            "docker-chunked",
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n\
+              7\r\n[{\"Id\":\r\n8;ext=1\r\n\"abc\"}]\n\r\n0\r\n\r\n",
+        );
+        let value = unix_http_get(&socket, "/containers/json?all=1").unwrap();
+        assert_eq!(value, json!([{"Id": "abc"}]));
+        assert_eq!(server.join().unwrap(), "GET /containers/json?all=1 HTTP/1.0\r\n");
+    }
+
+    #[test]
+    fn unix_http_get_reports_http_errors() {
+        let (socket, server) = stub_http_server(
+            "docker-404",
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\n{}",
+        );
+        let err = unix_http_get(&socket, "/containers/x/json").unwrap_err();
+        assert_eq!(err, "/containers/x/json: http 404");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unix_http_get_fails_without_socket() {
+        assert!(unix_http_get("/nonexistent/docker.sock", "/containers/json").is_err());
+    }
+
+    #[test]
+    fn decode_chunked_stops_at_last_chunk() {
+        assert_eq!(decode_chunked(b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"), b"abcde");
+        // 截断的分块不输出残缺数据
+        assert_eq!(decode_chunked(b"3\r\nabc\r\nA\r\nxy"), b"abc");
+    }
+
+    #[test]
+    fn decode_proc_addr_handles_host_byte_order() {
+        assert_eq!(decode_proc_addr("0100007F").as_deref(), Some("127.0.0.1"));
+        assert_eq!(decode_proc_addr("00000000").as_deref(), Some("0.0.0.0"));
+        assert_eq!(decode_proc_addr("00000000000000000000000001000000").as_deref(), Some("::1"));
+        assert_eq!(
+            decode_proc_addr("B80D0120000000000000000001000000").as_deref(),
+            Some("2001:db8::1")
+        );
+        assert_eq!(
+            decode_proc_addr("0000000000000000FFFF00000100007F").as_deref(),
+            Some("::ffff:127.0.0.1")
+        );
+        assert_eq!(decode_proc_addr("0100007"), None);
+        assert_eq!(decode_proc_addr("0100007G"), None);
+    }
+
+    #[test]
+    fn listening_skips_ephemeral_udp_ports() {
+        let header = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n";
+        let row = |local: &str, remote: &str, state: &str, inode: u32| {
+            format!("   0: {local} {remote} {state} 00000000:00000000 00:00000000 00000000     0        0 {inode}\n")
+        };
+        let tcp = format!("{header}{}", row("00000000:0016", "00000000:0000", "0A", 1));
+        let udp = format!(
+            "{header}{}{}{}",
+            row("00000000:0035", "00000000:0000", "07", 2),
+            // DNS 查询等客户端套接字使用的临时端口
+            row("00000000:9C40", "00000000:0000", "07", 3),
+            row("0100007F:0202", "0100007F:0035", "01", 4),
+        );
+        let root = fixture(
+            "listening",
+            &[
+                ("net/tcp", &tcp),
+                ("net/udp", &udp),
+                ("sys/net/ipv4/ip_local_port_range", "32768\t60999\n"),
+            ],
+        );
+        let ports: Vec<(String, u64)> = read_listening(&root, &[])
+            .iter()
+            .map(|s| (s["proto"].as_str().unwrap().to_string(), s["port"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(ports, [("tcp".to_string(), 22), ("udp".to_string(), 53)]);
+    }
+
+    #[test]
+    fn temperatures_from_hwmon_and_thermal_zones() {
+        let root = fixture(
+            "temperatures",
+            &[
+                ("class/hwmon/hwmon0/name", "coretemp\n"),
+                ("class/hwmon/hwmon0/temp1_input", "45500\n"),
+                ("class/hwmon/hwmon0/temp1_label", "Package id 0\n"),
+                ("class/hwmon/hwmon0/temp1_max", "80000\n"),
+                ("class/hwmon/hwmon0/temp1_crit", "100000\n"),
+                ("class/hwmon/hwmon0/temp10_input", "41000\n"),
+                ("class/hwmon/hwmon0/temp2_input", "43250\n"),
+                // 无读数的哨兵值
+                ("class/hwmon/hwmon0/temp3_input", "-273200\n"),
+                // 旧内核：文件位于 device/ 下
+                ("class/hwmon/hwmon1/device/name", "acpitz\n"),
+                ("class/hwmon/hwmon1/device/temp1_input", "27800\n"),
+                ("class/thermal/thermal_zone0/temp", "38000\n"),
+                ("class/thermal/thermal_zone0/type", "x86_pkg_temp\n"),
+                ("class/thermal/thermal_zone0/trip_point_0_type", "hot\n"),
+                ("class/thermal/thermal_zone0/trip_point_0_temp", "90000\n"),
+                ("class/thermal/thermal_zone0/trip_point_1_type", "critical\n"),
+                ("class/thermal/thermal_zone0/trip_point_1_temp", "105000\n"),
+                ("class/thermal/cooling_device0/type", "Processor\n"),
+            ],
+        );
+        let sensors = read_temperatures(&root);
+        let summary: Vec<(&str, &str, f64)> =
+            sensors.iter().map(|t| (t.sensor.as_str(), t.source, t.celsius)).collect();
+        assert_eq!(
+            summary,
+            [
+                ("coretemp/Package id 0", "hwmon", 45.5),
+                ("coretemp/temp2", "hwmon", 43.25),
+                ("coretemp/temp10", "hwmon", 41.0),
+                ("acpitz/temp1", "hwmon", 27.8),
+                ("thermal_zone0/x86_pkg_temp", "thermal", 38.0),
+            ]
+        );
+        assert_eq!((sensors[0].high, sensors[0].critical), (Some(80.0), Some(100.0)));
+        assert_eq!((sensors[1].high, sensors[1].critical), (None, None));
+        assert_eq!((sensors[4].high, sensors[4].critical), (Some(90.0), Some(105.0)));
+    }
+
+    #[test]
+    fn summarize_units_counts_active_and_failed() {
+        let unit = |name: &str, active: &str, sub: &str, path: &str| -> UnitRow {
+            let path = OwnedObjectPath::try_from(path).unwrap();
+            (
+                name.into(),
+                format!("{name} description"),
+                "loaded".into(),
+                active.into(),
+                sub.into(),
+                String::new(),
+                path,
+                0,
+                String::new(),
+                OwnedObjectPath::try_from("/").unwrap(),
+            )
+        };
+        let units = [
+            unit("sshd.service", "active", "running", "/org/freedesktop/systemd1/unit/sshd_2eservice"),
+            unit("nginx.service", "failed", "failed", "/org/freedesktop/systemd1/unit/nginx_2eservice"),
+            unit("tmp.mount", "inactive", "dead", "/org/freedesktop/systemd1/unit/tmp_2emount"),
+            unit("backup.timer", "failed", "failed", "/org/freedesktop/systemd1/unit/backup_2etimer"),
+        ];
+        let summary = summarize_units(&units, |path| {
+            path.as_str().ends_with("nginx_2eservice").then_some(1_700_000_000_123_456)
+        });
+        assert_eq!(
+            summary,
+            json!({
+                "units": 4,
+                "active": 1,
+                "failed": 2,
+                "failed_units": [
+                    {
+                        "name": "nginx.service",
+                        "description": "nginx.service description",
+                        "sub_state": "failed",
+                        "since": 1_700_000_000u64,
+                    },
+                    {
+                        "name": "backup.timer",
+                        "description": "backup.timer description",
+                        "sub_state": "failed",
+                        "since": null,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn per_core_usage_matches_cores_by_index() {
+        let times = |busy: u64, idle: u64| CpuTimes {
+            user: busy,
+            nice: 0,
+            system: 0,
+            idle,
+            iowait: 0,
+            irq: 0,
+            softirq: 0,
+            steal: 0,
+        };
+        let prev = [(0, times(100, 100)), (1, times(100, 100)), (2, times(100, 100))];
+        // cpu1 下线：cpu2 不能与 cpu1 的上一轮数据相减
+        let curr = [(0, times(150, 150)), (2, times(190, 110))];
+        assert_eq!(per_core_usage(&prev, &curr), [50.0, 90.0]);
+        // cpu1 重新上线时没有可比较的上一轮数据
+        let next = [(0, times(175, 225)), (1, times(300, 300)), (2, times(190, 210))];
+        assert_eq!(per_core_usage(&curr, &next), [25.0, 0.0, 0.0]);
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn run_config(args: &[String], env: &[(&str, &str)]) -> Result<Config, String> {
+        let env: HashMap<&str, &str> = env.iter().copied().collect();
+        match load_config(args, |name| env.get(name).map(|v| v.to_string()))? {
+            Invocation::Run(cfg) => Ok(*cfg),
+            Invocation::Help => Err("help".into()),
+            Invocation::Version => Err("version".into()),
+        }
+    }
+
+    #[test]
+    fn load_config_prefers_cli_over_env_over_file() {
+        let root = fixture(
+            "agent-config",
+            &[(
+                "agent.toml",
+                "token = \"file-token\"\nendpoint = \"http://file.example\"\ninterval = 10\n\
+                 flag = \"file\"\ntop_n = 3\nskip_fs = [\"tmpfs\", \"nfs\"]\n",
+            )],
+        );
+        let file = format!("{root}/agent.toml");
+        let env = [
+            ("IMONITOR_CONFIG", file.as_str()),
+            ("IMONITOR_INTERVAL", "20"),
+            ("IMONITOR_FLAG", "env"),
+            ("IMONITOR_ENDPOINT", "http://env.example"),
+        ];
+        let cfg = run_config(&args(&["--interval", "30", "--endpoint=http://cli.example"]), &env).unwrap();
+        assert_eq!(cfg.interval, 30);
+        assert_eq!(cfg.endpoint, "http://cli.example");
+        assert_eq!(cfg.flag, "env");
+        assert_eq!(cfg.top_n, 3);
+        assert_eq!(cfg.token, "file-token");
+        assert_eq!(cfg.skip_fs, ["tmpfs", "nfs"]);
+        assert_eq!(cfg.config_file.as_deref(), Some(Path::new(&file)));
+
+        let cfg = run_config(&[], &env).unwrap();
+        assert_eq!(cfg.interval, 20);
+        assert_eq!(cfg.endpoint, "http://env.example");
+    }
+
+    #[test]
+    fn load_config_rejects_unknown_input() {
+        let base = ["--token=t", "--endpoint=http://127.0.0.1", "--config="];
+        let with = |extra: &[&str]| {
+            let mut list = args(&base);
+            list.extend(args(extra));
+            run_config(&list, &[]).err()
+        };
+        assert_eq!(with(&[]), None);
+        assert_eq!(with(&["--bogus"]).as_deref(), Some("unknown option --bogus (see --help)"));
+        assert_eq!(with(&["stray"]).as_deref(), Some("unexpected argument 'stray' (see --help)"));
+        assert_eq!(with(&["--once=yes"]).as_deref(), Some("--once does not take a value"));
+        assert_eq!(with(&["--interval"]).as_deref(), Some("--interval requires a value"));
+        assert!(with(&["--interval=0"]).is_some());
+
+        let root = fixture("agent-config-bad", &[("agent.toml", "tokn = \"typo\"\n")]);
+        let file = format!("{root}/agent.toml");
+        let err = run_config(&args(&["--config", &file]), &[]).err().unwrap();
+        assert_eq!(err, format!("{file}: tokn: unknown key"));
+    }
+
+    #[test]
+    fn help_and_version_skip_validation() {
+        assert_eq!(run_config(&args(&["--bogus", "--help"]), &[]).err().as_deref(), Some("help"));
+        assert_eq!(run_config(&args(&["-h"]), &[]).err().as_deref(), Some("help"));
+        // 未提供令牌与面板地址
+        assert_eq!(run_config(&args(&["--version"]), &[]).err().as_deref(), Some("version"));
+    }
+
+    fn shell_plugin(script: &str, timeout: u64) -> PluginSpec {
+        PluginSpec {
+            name: "test".into(),
+            command: vec!["sh".into(), "-c".into(), script.into()],
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    #[test]
+    fn plugin_background_child_does_not_hold_output() {
+        let started = Instant::now();
+        let values = run_plugin(&shell_plugin("echo jobs 3; sleep 30 &", 5)).unwrap();
+        assert_eq!(values, vec![("jobs".to_string(), 3.0)]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn plugin_timeout_kills_process_group() {
+        let started = Instant::now();
+        let err = run_plugin(&shell_plugin("sleep 30 & sleep 30", 1)).unwrap_err();
+        assert_eq!(err, "timed out after 1s");
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn dropped_plugin_set_stops_running() {
+        let root = fixture("plugin-stop", &[]);
+        std::fs::create_dir_all(&root).unwrap();
+        let log = format!("{root}/runs");
+        let mut spec = shell_plugin(&format!("echo x >> {log}; echo runs 1"), 5);
+        spec.interval = Duration::from_millis(100);
+        let plugins = start_plugins(&[spec]);
+        thread::sleep(Duration::from_millis(500));
+        assert!(plugins.results.lock().unwrap().contains_key("test"));
+        drop(plugins);
+        thread::sleep(Duration::from_millis(300));
+        let runs = std::fs::read_to_string(&log).unwrap().lines().count();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), runs);
+    }
+
+    #[test]
+    fn custom_limit_drops_statsd_then_textfile() {
+        let prom: String = (0..200).map(|i| format!("text_{i:03} {i}\n")).collect();
+        let dir = fixture("custom-limit", &[("metrics.prom", &prom)]);
+        let plugins: PluginResults = Arc::new(Mutex::new(HashMap::new()));
+        plugins
+            .lock()
+            .unwrap()
+            .insert("zz".into(), (0..400).map(|i| (format!("p{i:03}"), i as f64)).collect());
+        let statsd = (0..100).map(|i| (format!("aa.statsd{i}"), 1.0)).collect();
+        let (custom, dropped) = collect_custom(&plugins, Some(&dir), statsd);
+        assert_eq!(custom.len(), MAX_CUSTOM_METRICS);
+        assert_eq!(dropped, 200);
+        assert!(custom.contains_key("zz.p399"));
+        assert!(custom.contains_key("text_099"));
+        assert!(!custom.contains_key("text_100"));
+        assert!(!custom.keys().any(|k| k.starts_with("aa.")));
+    }
+
+    #[test]
+    fn statsd_evicts_idle_counters() {
+        let mut state = StatsdState::default();
+        state.record("jobs:2|c");
+        assert!(state.flush().contains(&("jobs.count".to_string(), 2.0)));
+        for _ in 1..STATSD_COUNTER_IDLE_FLUSHES {
+            assert!(state.flush().contains(&("jobs.count".to_string(), 0.0)));
+        }
+        assert!(state.flush().contains(&("jobs.count".to_string(), 0.0)));
+        assert_eq!(state.tracked(), 0);
+        assert!(state.flush().is_empty());
+    }
+
+    #[test]
+    fn statsd_timer_samples_are_bounded() {
+        let mut state = StatsdState::default();
+        for i in 1..=5000 {
+            state.record(&format!("db.query:{i}|ms"));
+        }
+        assert_eq!(state.timers["db.query"].samples.len(), MAX_TIMER_SAMPLES);
+        let out: HashMap<String, f64> = state.flush().into_iter().collect();
+        assert_eq!(out["db.query.count"], 5000.0);
+        assert_eq!(out["db.query.min"], 1.0);
+        assert_eq!(out["db.query.max"], 5000.0);
+        assert_eq!(out["db.query.mean"], 2500.5);
+        // 抽样后的中位数应接近真实值
+        assert!((out["db.query.p50"] - 2500.0).abs() < 500.0);
+    }
+
+    #[test]
+    fn backlog_capacity_follows_remote_interval() {
+        let base = run_config(
+            &args(&[
+                "--token=t",
+                "--endpoint=http://127.0.0.1",
+                "--config=",
+                "--interval=1",
+                "--buffer-size=10",
+                "--flush-interval=60",
+            ]),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(base.backlog_capacity(), 61);
+        let mut backlog = VecDeque::new();
+        for i in 0..61 {
+            buffer_sample(&mut backlog, json!({"seq": i}), base.backlog_capacity());
+        }
+        assert_eq!(backlog.len(), 61);
+
+        let cfg = apply_remote_config(&base, json!({"interval": 30}).as_object().unwrap());
+        assert_eq!(cfg.backlog_capacity(), 10);
+        // 上限变小后只保留最新的样本
+        buffer_sample(&mut backlog, json!({"seq": 61}), cfg.backlog_capacity());
+        assert_eq!(backlog.len(), 10);
+        assert_eq!(backlog[0]["seq"], 52);
+        assert_eq!(backlog[9]["seq"], 61);
+        assert!(backlog[9]["timestamp"].is_f64());
+    }
+
+    #[test]
+    fn remote_config_overlays_local_settings() {
+        let base = run_config(
+            &args(&["--token=t", "--endpoint=http://127.0.0.1", "--config=", "--docker-socket=off", "--top-n=3"]),
+            &[],
+        )
+        .unwrap();
+        let settings = json!({
+            "interval": 15,
+            "top_n": 0,
+            "net_exclude": ["lo"],
+            "collectors": {"processes": false, "containers": true, "systemd": true},
+            "bogus": 1,
+        });
+        let cfg = apply_remote_config(&base, settings.as_object().unwrap());
+        assert_eq!(cfg.interval, 15);
+        // 非法取值保留本地设置
+        assert_eq!(cfg.top_n, 3);
+        assert_eq!(cfg.net_exclude, ["lo"]);
+        assert!(!cfg.enabled("processes"));
+        assert_eq!(cfg.systemd_bus.as_deref(), Some("system"));
+        assert_eq!(cfg.docker_socket.is_some(), Path::new(DEFAULT_DOCKER_SOCKET).exists());
+
+        // 撤销远程配置后恢复本地值
+        let cfg = apply_remote_config(&base, &Map::new());
+        assert_eq!(cfg.interval, base.interval);
+        assert!(cfg.enabled("processes"));
+        assert!(cfg.docker_socket.is_none());
+    }
+
+    #[test]
+    fn disk_io_rates_use_elapsed_time() {
+        let disk = |reads, sectors, ms, io_ms| DiskStat {
+            name: "sda".into(),
+            reads,
+            sectors_read: sectors,
+            read_ms: ms,
+            writes: 0,
+            sectors_written: 0,
+            write_ms: 0,
+            io_ms,
+        };
+        let io = compute_disk_io(&disk(100, 1000, 50, 100), &disk(300, 9000, 450, 1100), 2.0);
+        assert_eq!(io["read_bytes_per_sec"], 2_048_000);
+        assert_eq!(io["read_iops"], 100.0);
+        assert_eq!(io["await_ms"], 2.0);
+        assert_eq!(io["util_percent"], 50.0);
+    }
+
+    #[test]
+    fn process_cpu_uses_elapsed_time() {
+        let clk_tck = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        let sample = |pid, start_time, cpu_ticks| ProcSample {
+            pid,
+            name: format!("p{pid}"),
+            state: 'S',
+            threads: 1,
+            cpu_ticks,
+            start_time,
+            rss: pid as u64,
+        };
+        // pid 2 被复用（starttime 不同），不与上一轮相减
+        let prev = HashMap::from([(1, (10, 0)), (2, (20, 0))]);
+        let procs = [sample(1, 10, clk_tck), sample(2, 99, clk_tck * 4)];
+        let summary = summarize_processes(&procs, &prev, 4.0, 5);
+        assert_eq!(summary["total"], 2);
+        assert_eq!(summary["top_cpu"][0]["pid"], 1);
+        assert_eq!(summary["top_cpu"][0]["cpu"], 25.0);
+        assert_eq!(summary["top_cpu"][1]["cpu"], 0.0);
+        assert_eq!(summary["top_memory"][0]["pid"], 2);
+    }
+
+    #[test]
+    fn cgroup_cpu_uses_elapsed_time_and_quota() {
+        let sample = |usec| CgroupSample {
+            version: 2,
+            cpu_quota: Some(2.0),
+            cpu_usage_usec: Some(usec),
+            ..Default::default()
+        };
+        let (_, cpu) = cgroup_to_json(&sample(3_000_000), None, 8);
+        assert_eq!(cpu, None);
+        // 4 秒内用掉 4 核秒，配额 2 核即 50%
+        let (json, cpu) = cgroup_to_json(&sample(7_000_000), Some((&sample(3_000_000), 4.0)), 8);
+        assert_eq!(cpu, Some(50.0));
+        assert_eq!(json["cpu_percent"], 50.0);
+    }
+
+    #[test]
+    fn watch_matches_cmdline_under_proc_root() {
+        let root = fixture(
+            "watch-cmdline",
+            &[
+                ("10/cmdline", "java\0-jar\0/srv/app.jar\0"),
+                ("11/cmdline", "java\0-jar\0/srv/other.jar\0"),
+            ],
+        );
+        let proc = |pid, state| ProcSample {
+            pid,
+            name: "java".into(),
+            state,
+            threads: 1,
+            cpu_ticks: 0,
+            start_time: pid as u64 * 100,
+            rss: 0,
+        };
+        let procs = [proc(10, 'S'), proc(11, 'S'), proc(12, 'Z')];
+        let watches = parse_watches(&[
+            json!({"id": "w1", "kind": "process", "name": "app", "cmdline": "app\\.jar$"}),
+            json!({"id": "w2", "kind": "process", "name": "java"}),
+        ]);
+        assert_eq!(match_watch_processes(&watches[0], &root, &procs), [(10, 1000)]);
+        // 僵尸进程不算在运行
+        assert_eq!(match_watch_processes(&watches[1], &root, &procs), [(10, 1000), (11, 1100)]);
+    }
+
+    #[test]
+    fn default_skip_fs_covers_pseudo_and_network_filesystems() {
+        let cfg = run_config(&args(&["--token=t", "--endpoint=http://127.0.0.1", "--config="]), &[]).unwrap();
+        for fs in ["tmpfs", "overlay", "nfs4", "cifs", "fuse.sshfs"] {
+            assert!(cfg.skip_fs.iter().any(|s| s == fs), "{fs}");
+        }
+        assert_eq!(cfg.skip_fs.len(), PSEUDO_FS.len() + NETWORK_FS.len());
+    }
+
+    #[test]
+    fn disk_percent_sums_all_mounts() {
+        let mount = |used, free| MountUsage {
+            mount_point: "/".into(),
+            device: "/dev/sda1".into(),
+            fs_type: "ext4".into(),
+            usage: FsUsage {
+                total: used + free,
+                used,
+                free,
+                inodes_total: 0,
+                inodes_used: 0,
+            },
+        };
+        assert_eq!(total_disk_percent(&[mount(30, 70), mount(50, 50)]), Some(40.0));
+        assert_eq!(total_disk_percent(&[]), None);
+    }
+
+    #[test]
+    fn read_mounts_skips_types_and_bind_mounts() {
+        let root = fixture("mounts", &[("data dir/.keep", "")]);
+        let mountinfo = format!(
+            "22 1 8:1 / / rw - ext4 /dev/sda1 rw\n\
+             23 1 0:21 / /run rw - tmpfs tmpfs rw\n\
+             24 1 8:2 / {root}/data\\040dir rw - xfs /dev/sdb\\0401 rw\n\
+             25 1 8:2 /sub /mnt/bind rw - xfs /dev/sdb\\0401 rw\n\
+             malformed line\n"
+        );
+        let root = fixture("mounts-info", &[("mountinfo", &mountinfo)]);
+        let mounts = read_mounts(&format!("{root}/mountinfo"), &["tmpfs".to_string()]);
+        let points: Vec<&str> = mounts.iter().map(|m| m.mount_point.as_str()).collect();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0], "/");
+        assert!(points[1].ends_with("/data dir"));
+        assert_eq!(mounts[1].device, "/dev/sdb 1");
+        assert_eq!(unescape_mount_path(r"a\011b\\c\04"), "a\tb\\\\c\\04");
+    }
+
+    /// 在本地 TCP 端口上依次应答 `statuses`，每个连接一个请求；返回面板地址与收到的 "方法 路径"。
+    fn stub_panel(statuses: &'static [u16]) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = v.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let reply = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}"
+                );
+                reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                let mut parts = request_line.split_whitespace();
+                requests.push(format!("{} {}", parts.next().unwrap(), parts.next().unwrap()));
+            }
+            requests
+        });
+        (endpoint, handle)
+    }
+
+    fn test_uplink(endpoint: &str) -> Uplink {
+        let report_url = Url::parse(&format!("{endpoint}/api/report")).unwrap();
+        Uplink {
+            client: Client::new(),
+            batch_url: report_url.join("report/batch").unwrap(),
+            report_url,
+            compression: None,
+            encoding: "json".into(),
+            token: "t".into(),
+            batch_supported: true,
+            ws_url: None,
+            socket: None,
+            ws_failures: 0,
+            reconnect_at: None,
+            last_frame: Instant::now(),
+            inbox: Vec::new(),
+        }
+    }
+
+    fn samples(n: usize) -> VecDeque<Value> {
+        let mut backlog = VecDeque::new();
+        for i in 0..n {
+            buffer_sample(&mut backlog, json!({"seq": i}), 10);
+        }
+        backlog
+    }
+
+    #[test]
+    fn backlog_falls_back_to_single_posts_only_without_batch_endpoint() {
+        let (endpoint, server) = stub_panel(&[404, 200, 200, 200]);
+        let mut uplink = test_uplink(&endpoint);
+        let mut backlog = samples(2);
+        assert!(uplink.flush_backlog(&mut backlog).is_some());
+        assert!(backlog.is_empty());
+        assert!(!uplink.batch_supported);
+        // 记住批量接口不存在，下一轮直接逐条发送
+        let mut backlog = samples(1);
+        uplink.flush_backlog(&mut backlog);
+        assert!(backlog.is_empty());
+        assert_eq!(
+            server.join().unwrap(),
+            ["POST /api/report/batch", "POST /api/report", "POST /api/report", "POST /api/report"]
+        );
+    }
+
+    #[test]
+    fn rejected_batch_is_dropped_without_single_posts() {
+        let (endpoint, server) = stub_panel(&[400, 200]);
+        let mut uplink = test_uplink(&endpoint);
+        let mut backlog = samples(3);
+        assert!(uplink.flush_backlog(&mut backlog).is_none());
+        assert!(backlog.is_empty());
+        assert!(uplink.batch_supported);
+        let mut backlog = samples(2);
+        uplink.flush_backlog(&mut backlog);
+        assert!(backlog.is_empty());
+        assert_eq!(server.join().unwrap(), ["POST /api/report/batch", "POST /api/report/batch"]);
+    }
+
+    #[test]
+    fn release_path_stays_under_agent_dir() {
+        assert_eq!(release_path(&json!({"url": "/agent/aarch64"})), Ok("/agent/aarch64"));
+        assert_eq!(release_path(&json!({})), Ok("/agent.bin"));
+        for bad in ["@evil.example/agent", "/agent/../api/x", "/install.sh", "//evil.example/agent/x"] {
+            assert!(release_path(&json!({"url": bad})).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn glob_match_supports_star_and_question_mark() {
+        assert!(glob_match("veth*", "veth1a2b"));
+        assert!(glob_match("eth?", "eth0"));
+        assert!(!glob_match("eth?", "eth10"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("br-*-x", "br-abc-x"));
+        assert!(!glob_match("br-*-x", "br-abc-y"));
+        assert!(glob_match("lo", "lo"));
+        assert!(!glob_match("lo", "lo0"));
+    }
+
+    #[test]
+    fn net_dev_applies_include_and_exclude() {
+        let root = fixture(
+            "net-dev",
+            &[(
+                "dev",
+                "Inter-|   Receive                                                |  Transmit\n \
+                 face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n\
+                 \x20   lo: 100 1 0 0 0 0 0 0 100 1 0 0 0 0 0 0\n\
+                 \x20 eth0: 2000 20 1 2 0 0 0 0 3000 30 3 4 0 0 0 0\n\
+                 veth12: 5 1 0 0 0 0 0 0 5 1 0 0 0 0 0 0\n\
+                 \x20 wlan0: 7 1 0 0 0 0 0 0 9 1 0 0 0 0 0 0\n",
+            )],
+        );
+        let path = format!("{root}/dev");
+        let exclude: Vec<String> = DEFAULT_NET_EXCLUDE.iter().map(|s| s.to_string()).collect();
+        let ifaces = read_net_dev(&path, &[], &exclude);
+        let names: Vec<&str> = ifaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["eth0", "wlan0"]);
+        assert_eq!((ifaces[0].rx_bytes, ifaces[0].rx_errors, ifaces[0].rx_drops), (2000, 1, 2));
+        assert_eq!((ifaces[0].tx_bytes, ifaces[0].tx_errors, ifaces[0].tx_drops), (3000, 3, 4));
+        let ifaces = read_net_dev(&path, &["eth*".to_string()], &exclude);
+        assert_eq!(ifaces.len(), 1);
+    }
+
+    #[test]
+    fn meminfo_converts_kb_and_keeps_hugepage_counts() {
+        let root = fixture(
+            "meminfo",
+            &[(
+                "meminfo",
+                "MemTotal:        8000 kB\nMemFree:         1000 kB\nMemAvailable:    6000 kB\n\
+                 Buffers:          200 kB\nCached:          3000 kB\nShmem:            100 kB\n\
+                 Dirty:             12 kB\nSwapTotal:       2048 kB\nSwapFree:        1024 kB\n\
+                 HugePages_Total:      4\nHugePages_Free:       1\nHugepagesize:    2048 kB\n",
+            )],
+        );
+        let mem = read_meminfo(&format!("{root}/meminfo")).unwrap();
+        assert_eq!(mem.total, 8000 * 1024);
+        assert_eq!(mem.used(), 2000 * 1024);
+        assert_eq!((mem.buffers, mem.cached, mem.shared, mem.dirty), (200 * 1024, 3000 * 1024, 100 * 1024, 12 * 1024));
+        assert_eq!((mem.swap_total, mem.swap_free), (2048 * 1024, 1024 * 1024));
+        assert_eq!((mem.hugepages_total, mem.hugepages_free, mem.hugepage_size), (4, 1, 2 << 20));
+        assert!(read_meminfo(&format!("{root}/missing")).is_none());
+    }
+
+    #[test]
+    fn pressure_reads_some_and_full_averages() {
+        let root = fixture(
+            "pressure",
+            &[
+                ("cpu", "some avg10=1.50 avg60=0.75 avg300=0.10 total=123\n"),
+                (
+                    "memory",
+                    "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\nfull avg10=2.00 avg60=1.00 avg300=0.50 total=9\n",
+                ),
+            ],
+        );
+        let psi = read_pressure(&root).unwrap();
+        assert_eq!(psi["cpu"]["some"], json!({"avg10": 1.5, "avg60": 0.75, "avg300": 0.1}));
+        assert_eq!(psi["memory"]["full"]["avg10"], 2.0);
+        // 缺失的资源（如 io）不上报，total 不上报
+        assert!(psi.get("io").is_none());
+        assert!(psi["cpu"]["some"].get("total").is_none());
+        assert!(read_pressure(&format!("{root}/missing")).is_none());
+    }
+
+    #[test]
+    fn socket_summary_counts_states_and_sockstat() {
+        let header = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n";
+        let row = |local: &str, remote: &str, state: &str| {
+            format!("   0: {local} {remote} {state} 00000000:00000000 00:00000000 00000000     0        0 1\n")
+        };
+        let tcp = format!(
+            "{header}{}{}{}",
+            row("00000000:0016", "00000000:0000", "0A"),
+            row("0100007F:0016", "0100007F:C000", "01"),
+            row("0100007F:0016", "0100007F:C001", "06"),
+        );
+        let tcp6 = format!("{header}{}", row("00000000000000000000000000000000:0016", "00000000000000000000000000000000:0000", "0A"));
+        let udp = format!("{header}{}", row("00000000:0035", "00000000:0000", "07"));
+        let root = fixture(
+            "sockets",
+            &[
+                ("net/tcp", &tcp),
+                ("net/tcp6", &tcp6),
+                ("net/udp", &udp),
+                ("net/sockstat", "sockets: used 120\nTCP: inuse 4 orphan 0 tw 6 alloc 5 mem 1\nUDP: inuse 2 mem 0\n"),
+            ],
+        );
+        let summary = read_socket_summary(&root);
+        assert_eq!(summary["tcp"], json!({"LISTEN": 2, "ESTABLISHED": 1, "TIME_WAIT": 1}));
+        assert_eq!(summary["tcp_total"], 4);
+        assert_eq!(summary["udp_total"], 1);
+        // IPv4 与 IPv6 监听同一端口只算一次
+        assert_eq!(summary["listen_tcp"], json!([22]));
+        assert_eq!(summary["listen_udp"], json!([53]));
+        assert_eq!(summary["sockstat"]["tcp_tw"], 6);
+        assert_eq!(summary["sockstat"]["sockets_used"], 120);
+    }
+
+    #[test]
+    fn conntrack_reports_usage_when_module_is_loaded() {
+        let root = fixture("conntrack", &[("nf_conntrack_count", "512\n"), ("nf_conntrack_max", "2048\n")]);
+        assert_eq!(read_conntrack(&root), Some(json!({"count": 512, "max": 2048, "percent": 25.0})));
+        assert_eq!(read_conntrack(&format!("{root}/missing")), None);
+    }
+
+    #[test]
+    fn plugin_output_parses_json_and_key_value_lines() {
+        let json = parse_plugin_output(r#" {"queue": {"depth": 3, "ok": true}, "name": "x", "load": 0.5, "list": [1]} "#);
+        assert_eq!(
+            json,
+            vec![
+                ("load".to_string(), 0.5),
+                ("queue.depth".to_string(), 3.0),
+                ("queue.ok".to_string(), 1.0),
+            ]
+        );
+        let lines = parse_plugin_output(
+            "# comment\n\nconnections 12\ntemp=-3.5\nratio: 0.25\nstatus up\nlatency = 7\nnan NaN\n",
+        );
+        assert_eq!(
+            lines,
+            vec![
+                ("connections".to_string(), 12.0),
+                ("temp".to_string(), -3.5),
+                ("ratio".to_string(), 0.25),
+                ("latency".to_string(), 7.0),
+            ]
+        );
+        assert!(parse_plugin_output("{not json").is_empty());
+    }
+
+    #[test]
+    fn textfiles_keep_labels_and_skip_timestamps() {
+        let root = fixture(
+            "textfiles",
+            &[
+                (
+                    "b.prom",
+                    "# HELP jobs queued jobs\n# TYPE jobs gauge\njobs{queue=\"mail out\"} 4 1700000000000\nbackup_ok 1\n",
+                ),
+                ("a.prom", "temp_celsius 41.5\nbroken{x=\"1\" 2\nbad_value abc\n"),
+                ("c.txt", "ignored 1\n"),
+            ],
+        );
+        assert_eq!(
+            read_textfiles(&root),
+            vec![
+                ("temp_celsius".to_string(), 41.5),
+                ("jobs{queue=\"mail out\"}".to_string(), 4.0),
+                ("backup_ok".to_string(), 1.0),
+            ]
+        );
+        assert!(read_textfiles(&format!("{root}/missing")).is_empty());
+    }
+
+    #[test]
+    fn statsd_aggregates_each_metric_type() {
+        let mut state = StatsdState::default();
+        state.record("hits:1|c|@0.1|#env:prod");
+        state.record("hits:2|c");
+        state.record("temp:20|g");
+        state.record("temp:+3|g");
+        state.record("temp:-1.5|g");
+        for i in 1..=100 {
+            state.record(&format!("req:{i}|ms"));
+        }
+        state.record("users:alice|s");
+        state.record("users:bob|s");
+        state.record("users:alice|s");
+        state.record("bad:abc|c");
+        state.record(":1|c");
+        state.record("nokind:1");
+        let out: HashMap<String, f64> = state.flush().into_iter().collect();
+        assert_eq!(out["hits.count"], 12.0);
+        assert_eq!(out["hits.rate"], 12.0);
+        assert_eq!(out["temp"], 21.5);
+        assert_eq!(out["req.count"], 100.0);
+        assert_eq!(out["req.p50"], 50.0);
+        assert_eq!(out["req.p95"], 95.0);
+        assert_eq!(out["req.p99"], 99.0);
+        assert_eq!(out["users.count"], 2.0);
+        assert_eq!(out.len(), 11);
+        // 计时器与集合按周期清空，gauge 保留
+        let out: HashMap<String, f64> = state.flush().into_iter().collect();
+        assert_eq!(out["hits.count"], 0.0);
+        assert_eq!(out["temp"], 21.5);
+        assert!(!out.contains_key("req.count") && !out.contains_key("users.count"));
+    }
+
+    #[test]
+    fn statsd_rejects_new_names_over_the_limit() {
+        let mut state = StatsdState::default();
+        for i in 0..MAX_STATSD_METRICS {
+            state.record(&format!("g{i}:1|g"));
+        }
+        state.record("extra:1|c");
+        state.record("g0:5|g");
+        assert_eq!(state.tracked(), MAX_STATSD_METRICS);
+        assert!(!state.counters.contains_key("extra"));
+        assert_eq!(state.gauges["g0"], 5.0);
+    }
+}
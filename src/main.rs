@@ -1,14 +1,23 @@
 use std::{
+    collections::HashMap,
+    io::Read,
     net::SocketAddr,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
-    extract::{ConnectInfo, Multipart, Path as AxumPath, State},
+    body::Bytes,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Multipart, Path as AxumPath, Query, State,
+    },
     http::{header, HeaderMap, StatusCode},
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Response},
     routing::{delete, get, post},
     Json, Router,
 };
@@ -16,15 +25,28 @@ use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
 use rand::Rng;
 use rusqlite::{params, Connection};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tokio::{fs, net::TcpListener, signal, sync::RwLock};
+use tokio::{
+    fs,
+    net::TcpListener,
+    signal,
+    sync::{mpsc, RwLock},
+};
 use tower_http::services::ServeDir;
-use tracing::{error, info};
+use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
+mod alerts;
+
+use alerts::{
+    create_alert_rule_handler, delete_alert_rule_handler, evaluate_alerts, evaluate_watch_alerts,
+    list_alert_rules_handler, list_alerts_handler,
+};
+
 #[derive(Clone)]
 struct Settings {
     public_url: String,
@@ -41,6 +63,76 @@ struct AppState {
     scripts_dir: Arc<PathBuf>,
     data_dir: Arc<PathBuf>,
     app_settings: Arc<AppSettings>,
+    agents: Arc<AgentHub>,
+    /// 按文件修改时间与大小缓存 Agent 分发文件的 SHA-256
+    file_digests: Arc<DigestCache>,
+}
+
+type DigestCache = StdMutex<HashMap<PathBuf, (SystemTime, u64, String)>>;
+
+/// 支持分发的架构及对应的 musl loader 文件名（与二进制中的解释器路径一致）。
+const AGENT_ARCHES: &[(&str, &str)] = &[
+    ("x86_64", "ld-musl-x86_64.so.1"),
+    ("aarch64", "ld-musl-aarch64.so.1"),
+    ("armv7", "ld-musl-armhf.so.1"),
+    ("riscv64", "ld-musl-riscv64.so.1"),
+];
+
+#[derive(Clone, Serialize)]
+struct AgentFile {
+    name: String,
+    url: String,
+    sha256: String,
+    size: u64,
+}
+
+#[derive(Clone, Serialize)]
+struct AgentRelease {
+    arch: String,
+    version: String,
+    sha256: String,
+    size: u64,
+    url: String,
+    loader: Option<AgentFile>,
+}
+
+/// 通过 WebSocket 保持连接的 Agent，用于面板向 Agent 下发消息。
+#[derive(Default)]
+struct AgentHub {
+    links: StdMutex<HashMap<String, (u64, mpsc::UnboundedSender<Value>)>>,
+    next_id: AtomicU64,
+}
+
+impl AgentHub {
+    /// 同一节点重连时替换旧连接，返回本连接的序号。
+    fn register(&self, token: &str, tx: mpsc::UnboundedSender<Value>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.links.lock().unwrap().insert(token.to_string(), (id, tx));
+        id
+    }
+
+    /// 只有仍是当前连接时才移除，返回是否移除。
+    fn unregister(&self, token: &str, id: u64) -> bool {
+        let mut links = self.links.lock().unwrap();
+        if links.get(token).is_some_and(|(current, _)| *current == id) {
+            links.remove(token);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn tokens(&self) -> Vec<String> {
+        self.links.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn send(&self, token: &str, message: Value) -> bool {
+        self.links
+            .lock()
+            .unwrap()
+            .get(token)
+            .is_some_and(|(_, tx)| tx.send(message).is_ok())
+    }
 }
 
 #[derive(Serialize)]
@@ -61,6 +153,8 @@ struct NodeResponse {
     token: String,
     meta: Option<Value>,
     metrics: Option<Value>,
+    traffic: Option<TrafficSummary>,
+    tags: Vec<String>,
 }
 
 struct NodeRaw {
@@ -73,6 +167,8 @@ struct NodeRaw {
     last_seen: Option<f64>,
     meta: Option<String>,
     metrics: Option<String>,
+    tags: Option<String>,
+    link: Option<String>,
 }
 
 impl NodeRaw {
@@ -82,6 +178,8 @@ impl NodeRaw {
             .unwrap_or_default()
             .as_secs_f64();
         let status = match self.last_seen {
+            // WebSocket 断开即视为离线，不必等待超时
+            Some(_) if self.link.as_deref() == Some("closed") => "offline".to_string(),
             Some(ts) if now - ts <= offline_timeout as f64 => "online".to_string(),
             Some(_) => "offline".to_string(),
             None => "pending".to_string(),
@@ -94,6 +192,10 @@ impl NodeRaw {
             Some(ref m) => Some(serde_json::from_str(m).map_err(AppError::Serde)?),
             None => None,
         };
+        let tags = match self.tags {
+            Some(ref t) => serde_json::from_str(t).map_err(AppError::Serde)?,
+            None => Vec::new(),
+        };
         Ok(NodeResponse {
             id: self.id,
             token: self.token,
@@ -105,6 +207,8 @@ impl NodeRaw {
             status,
             meta: meta_value,
             metrics: metrics_value,
+            traffic: None,
+            tags,
         })
     }
 }
@@ -131,6 +235,12 @@ struct ReportPayload {
     meta: Map<String, Value>,
     #[serde(default)]
     metrics: Map<String, Value>,
+    /// Agent 离线期间缓存的样本带有原始采集时间，实时上报不带
+    #[serde(default)]
+    timestamp: Option<f64>,
+    /// Agent 当前生效的远程配置版本，与面板不一致时在响应中下发新配置
+    #[serde(default)]
+    config_version: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -147,6 +257,10 @@ enum AppError {
     Serde(#[from] serde_json::Error),
     #[error("bad request: {0}")]
     BadRequest(String),
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
 }
 
 impl IntoResponse for AppError {
@@ -155,6 +269,8 @@ impl IntoResponse for AppError {
             AppError::NotFound => StatusCode::NOT_FOUND,
             AppError::Unauthorized => StatusCode::UNAUTHORIZED,
             AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
         let body = Json(json!({"detail": self.to_string()}));
@@ -202,6 +318,8 @@ async fn main() -> anyhow::Result<()> {
         scripts_dir: Arc::new(scripts_dir),
         data_dir: Arc::new(data_dir),
         app_settings,
+        agents: Arc::new(AgentHub::default()),
+        file_digests: Arc::new(StdMutex::new(HashMap::new())),
     };
 
     let app = Router::new()
@@ -209,11 +327,36 @@ async fn main() -> anyhow::Result<()> {
         .route("/install.sh", get(install_script))
         .route("/agent.bin", get(agent_binary))
         .route("/ld-musl-x86_64.so.1", get(musl_loader))
+        .route("/agent/SHA256SUMS", get(agent_checksums_handler))
+        .route("/agent/:arch", get(agent_arch_binary))
+        .route("/agent/:arch/loader", get(agent_arch_loader))
         .route("/api/nodes", get(list_nodes_handler))
         .route("/api/nodes/reserve", post(reserve_node))
         .route("/api/login", post(login_handler))
         .route("/api/report", post(report_handler))
+        .route("/api/report/batch", post(report_batch_handler))
+        .route("/api/agent/ws", get(agent_socket_handler))
+        .route("/api/agent/config", get(agent_config_handler))
+        .route("/api/agent/release", get(agent_release_handler))
+        .route("/api/agent/manifest", get(agent_manifest_handler))
+        .route("/api/agent/rollout", post(agent_rollout_handler))
         .route("/api/nodes/:token", delete(delete_node_handler).patch(update_node_handler))
+        .route(
+            "/api/nodes/:token/traffic",
+            get(get_traffic_handler).patch(update_traffic_handler),
+        )
+        .route("/api/nodes/:token/events", get(list_node_events_handler))
+        .route("/api/nodes/:token/history", get(get_history_handler))
+        .route("/api/nodes/:token/custom", get(get_custom_history_handler))
+        .route("/api/nodes/:token/message", post(send_agent_message_handler))
+        .route("/api/agent-configs", get(list_agent_configs_handler).put(put_agent_config_handler))
+        .route("/api/agent-configs/:id", delete(delete_agent_config_handler))
+        .route("/api/nodes/:token/agent-config", get(get_node_agent_config_handler))
+        .route("/api/watches", get(list_watches_handler).post(create_watch_handler))
+        .route("/api/watches/:id", delete(delete_watch_handler))
+        .route("/api/alerts", get(list_alerts_handler))
+        .route("/api/alerts/rules", get(list_alert_rules_handler).post(create_alert_rule_handler))
+        .route("/api/alerts/rules/:id", delete(delete_alert_rule_handler))
         .route("/api/settings", get(get_settings_handler))
         .route("/api/settings/background", post(update_background_handler))
         .route("/api/settings/background/upload", post(update_background_upload_handler))
@@ -280,23 +423,173 @@ async fn install_script(State(state): State<AppState>) -> Result<impl IntoRespon
 }
 
 async fn agent_binary(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
-    let path = state.scripts_dir.join("agent");
-    if !path.exists() {
-        return Err(AppError::NotFound);
-    }
+    serve_agent_file(agent_path(&state.scripts_dir, "x86_64")).await
+}
+
+async fn musl_loader(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    serve_agent_file(loader_path(&state.scripts_dir, "x86_64")).await
+}
+
+async fn agent_arch_binary(
+    State(state): State<AppState>,
+    AxumPath(arch): AxumPath<String>,
+) -> Result<impl IntoResponse, AppError> {
+    serve_agent_file(agent_path(&state.scripts_dir, &arch)).await
+}
+
+async fn agent_arch_loader(
+    State(state): State<AppState>,
+    AxumPath(arch): AxumPath<String>,
+) -> Result<impl IntoResponse, AppError> {
+    serve_agent_file(loader_path(&state.scripts_dir, &arch)).await
+}
+
+async fn serve_agent_file(path: Option<PathBuf>) -> Result<impl IntoResponse, AppError> {
+    let path = path.ok_or(AppError::NotFound)?;
     let bytes = fs::read(path).await?;
     let headers = [(header::CONTENT_TYPE, "application/octet-stream")];
     Ok((headers, bytes))
 }
 
-async fn musl_loader(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
-    let path = state.scripts_dir.join("ld-musl-x86_64.so.1");
-    if !path.exists() {
-        return Err(AppError::NotFound);
+/// 各架构的 Agent 放在 scripts/agent-<arch>；x86_64 兼容旧的 scripts/agent。
+fn agent_path(scripts_dir: &Path, arch: &str) -> Option<PathBuf> {
+    AGENT_ARCHES.iter().find(|(a, _)| *a == arch)?;
+    let path = scripts_dir.join(format!("agent-{arch}"));
+    if path.exists() {
+        return Some(path);
+    }
+    let legacy = scripts_dir.join("agent");
+    (arch == "x86_64" && legacy.exists()).then_some(legacy)
+}
+
+fn loader_path(scripts_dir: &Path, arch: &str) -> Option<PathBuf> {
+    let (_, loader) = AGENT_ARCHES.iter().find(|(a, _)| *a == arch)?;
+    let path = scripts_dir.join(loader);
+    path.exists().then_some(path)
+}
+
+async fn file_digest(state: &AppState, path: &Path) -> Result<(String, u64), AppError> {
+    let meta = fs::metadata(path).await?;
+    let modified = meta.modified()?;
+    if let Some((mtime, size, sha256)) = state.file_digests.lock().unwrap().get(path) {
+        if *mtime == modified && *size == meta.len() {
+            return Ok((sha256.clone(), *size));
+        }
     }
     let bytes = fs::read(path).await?;
-    let headers = [(header::CONTENT_TYPE, "application/octet-stream")];
-    Ok((headers, bytes))
+    let sha256 = format!("{:x}", Sha256::digest(&bytes));
+    state
+        .file_digests
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (modified, meta.len(), sha256.clone()));
+    Ok((sha256, meta.len()))
+}
+
+/// 版本号取 scripts/agent.version，缺省为面板版本。
+async fn agent_version(state: &AppState) -> String {
+    match fs::read_to_string(state.scripts_dir.join("agent.version")).await {
+        Ok(v) if !v.trim().is_empty() => v.trim().to_string(),
+        _ => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// 指定架构当前提供下载的 Agent 及其 loader 的 SHA-256。
+async fn agent_release(state: &AppState, arch: &str) -> Result<AgentRelease, AppError> {
+    let path = agent_path(&state.scripts_dir, arch).ok_or(AppError::NotFound)?;
+    let (sha256, size) = file_digest(state, &path).await?;
+    let loader = match loader_path(&state.scripts_dir, arch) {
+        Some(path) => {
+            let (sha256, size) = file_digest(state, &path).await?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            Some(AgentFile { name, url: format!("/agent/{arch}/loader"), sha256, size })
+        }
+        None => None,
+    };
+    Ok(AgentRelease {
+        arch: arch.to_string(),
+        version: agent_version(state).await,
+        sha256,
+        size,
+        url: format!("/agent/{arch}"),
+        loader,
+    })
+}
+
+async fn available_releases(state: &AppState) -> Result<Vec<AgentRelease>, AppError> {
+    let mut releases = Vec::new();
+    for (arch, _) in AGENT_ARCHES {
+        if agent_path(&state.scripts_dir, arch).is_some() {
+            releases.push(agent_release(state, arch).await?);
+        }
+    }
+    Ok(releases)
+}
+
+#[derive(Deserialize)]
+struct ReleaseQuery {
+    arch: Option<String>,
+}
+
+/// 旧版 Agent 不带 arch 参数，均为 x86_64。
+async fn agent_release_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ReleaseQuery>,
+) -> Result<Json<AgentRelease>, AppError> {
+    let arch = query.arch.as_deref().unwrap_or("x86_64");
+    Ok(Json(agent_release(&state, arch).await?))
+}
+
+async fn agent_manifest_handler(State(state): State<AppState>) -> Result<Json<Value>, AppError> {
+    let builds = available_releases(&state).await?;
+    Ok(Json(json!({"version": agent_version(&state).await, "builds": builds})))
+}
+
+/// sha256sum 格式的校验和列表，供 install.sh 用 `sha256sum -c` 校验。
+async fn agent_checksums_handler(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let mut lines = String::new();
+    for release in available_releases(&state).await? {
+        lines.push_str(&format!("{}  agent-{}\n", release.sha256, release.arch));
+        if let Some(loader) = release.loader {
+            lines.push_str(&format!("{}  {}\n", loader.sha256, loader.name));
+        }
+    }
+    let headers = [(header::CONTENT_TYPE, "text/plain; charset=utf-8")];
+    Ok((headers, lines))
+}
+
+#[derive(Deserialize, Default)]
+struct RolloutRequest {
+    #[serde(default)]
+    tokens: Vec<String>,
+    tag: Option<String>,
+}
+
+/// 通知通过 WebSocket 连接的 Agent 立即检查更新；HTTP 模式的 Agent 按自身周期检查。
+async fn agent_rollout_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<RolloutRequest>>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let req = body.map(|Json(b)| b).unwrap_or_default();
+    let version = agent_version(&state).await;
+    let conn = Connection::open(state.data_dir.join("imonitor.db"))?;
+    let mut notified = Vec::new();
+    for token in state.agents.tokens() {
+        if !req.tokens.is_empty() && !req.tokens.contains(&token) {
+            continue;
+        }
+        if let Some(tag) = &req.tag {
+            if !node_tags(&conn, &token).contains(tag) {
+                continue;
+            }
+        }
+        if state.agents.send(&token, json!({"type": "update", "version": version})) {
+            notified.push(token);
+        }
+    }
+    Ok(Json(json!({"version": version, "notified": notified})))
 }
 
 async fn list_nodes_handler(
@@ -341,8 +634,196 @@ async fn reserve_node(
 async fn report_handler(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Json(payload): Json<ReportPayload>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let payload: ReportPayload = decode_report(&headers, &body)?;
+    let db_path = state.data_dir.join("imonitor.db");
+    ingest_report(&db_path, &payload, addr)?;
+    let conn = Connection::open(&db_path)?;
+    let watches = watches_for_node(&conn, &payload.token)?;
+    let mut reply = json!({"status": "ok", "watches": watches});
+    attach_agent_config(&conn, &payload, &mut reply)?;
+    encode_reply(&headers, &reply)
+}
+
+/// 批量上报：请求体为样本数组，可用 `Content-Encoding: gzip` 或 `zstd` 压缩。
+/// 单个样本无效（如 token 不存在）时跳过，只有数据库等内部错误才让整批失败以便 Agent 重试。
+async fn report_batch_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let samples: Vec<ReportPayload> = decode_report(&headers, &body)?;
+    let db_path = state.data_dir.join("imonitor.db");
+    let mut accepted = 0;
+    let mut rejected = 0;
+    for sample in &samples {
+        match ingest_report(&db_path, sample, addr) {
+            Ok(()) => accepted += 1,
+            Err(AppError::NotFound | AppError::BadRequest(_)) => rejected += 1,
+            Err(err) => return Err(err),
+        }
+    }
+    let mut reply = json!({
+        "status": "ok",
+        "accepted": accepted,
+        "rejected": rejected,
+    });
+    if let Some(sample) = samples.last() {
+        let conn = Connection::open(&db_path)?;
+        reply["watches"] = json!(watches_for_node(&conn, &sample.token)?);
+        attach_agent_config(&conn, sample, &mut reply)?;
+    }
+    encode_reply(&headers, &reply)
+}
+
+// 超过该时长没有收到任何帧（样本或心跳）即断开
+const WS_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Deserialize)]
+struct AgentSocketQuery {
+    /// 旧版 Agent 把 token 放在查询参数里，新版使用 Authorization: Bearer
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    encoding: Option<String>,
+}
+
+async fn agent_socket_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<AgentSocketQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let token = bearer_token(&headers).or(query.token).ok_or(AppError::Unauthorized)?;
+    let format = match query.encoding.as_deref() {
+        None | Some("json") => WireFormat::Json,
+        Some("cbor") => WireFormat::Cbor,
+        Some("msgpack") => WireFormat::MsgPack,
+        Some(other) => return Err(AppError::UnsupportedMediaType(other.to_string())),
+    };
+    // 握手前校验 token，无效节点不建立连接
+    let db_path = state.data_dir.join("imonitor.db");
+    set_node_link(&db_path, &token, "ws")?;
+    Ok(ws.on_upgrade(move |socket| agent_socket(state, socket, token, addr, format)))
+}
+
+/// Agent 通过 WebSocket 逐条推送样本，每条回复一个 ack；
+/// 面板下发的消息经 AgentHub 的通道写入同一连接。
+async fn agent_socket(state: AppState, mut socket: WebSocket, token: String, addr: SocketAddr, format: WireFormat) {
+    let db_path = state.data_dir.join("imonitor.db");
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    let link_id = state.agents.register(&token, tx);
+    info!("agent {token} connected via websocket from {addr}");
+    // 只有 Agent 发来的帧才顺延期限，面板下发消息不算
+    let mut deadline = tokio::time::Instant::now() + WS_IDLE_TIMEOUT;
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                let message = match incoming {
+                    Some(Ok(message)) => message,
+                    Some(Err(err)) => {
+                        warn!("agent {token} websocket error: {err}");
+                        break;
+                    }
+                    None => break,
+                };
+                deadline = tokio::time::Instant::now() + WS_IDLE_TIMEOUT;
+                // 文本帧总是 JSON，二进制帧按握手时约定的编码解析
+                let (decoder, raw) = match message {
+                    Message::Text(text) => (WireFormat::Json, text.into_bytes()),
+                    Message::Binary(data) => (format, data),
+                    Message::Close(_) => break,
+                    // Ping 由底层自动回复 Pong，只用来刷新超时
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                };
+                let reply = match ingest_socket_sample(&db_path, &token, addr, decoder, &raw) {
+                    Ok(reply) => reply,
+                    Err(err) => json!({"type": "error", "detail": err.to_string()}),
+                };
+                if send_socket(&mut socket, format, &reply).await.is_err() {
+                    break;
+                }
+            }
+            Some(outgoing) = rx.recv() => {
+                if send_socket(&mut socket, format, &outgoing).await.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                warn!("agent {token} websocket heartbeat timed out");
+                break;
+            }
+        }
+    }
+    if state.agents.unregister(&token, link_id) {
+        if let Err(err) = set_node_link(&db_path, &token, "closed") {
+            warn!("failed to mark {token} offline: {err}");
+        }
+        info!("agent {token} websocket closed");
+    }
+}
+
+fn ingest_socket_sample(
+    db_path: &Path,
+    token: &str,
+    addr: SocketAddr,
+    format: WireFormat,
+    raw: &[u8],
+) -> Result<Value, AppError> {
+    let payload: ReportPayload = format.decode(raw)?;
+    if payload.token != token {
+        return Err(AppError::BadRequest("token mismatch".into()));
+    }
+    ingest_report(db_path, &payload, addr)?;
+    let conn = Connection::open(db_path)?;
+    let watches = watches_for_node(&conn, token)?;
+    let mut reply = json!({"type": "ack", "watches": watches});
+    attach_agent_config(&conn, &payload, &mut reply)?;
+    Ok(reply)
+}
+
+async fn send_socket(socket: &mut WebSocket, format: WireFormat, value: &Value) -> Result<(), AppError> {
+    let message = match format {
+        WireFormat::Json => Message::Text(value.to_string()),
+        other => Message::Binary(other.encode(value)?),
+    };
+    socket
+        .send(message)
+        .await
+        .map_err(|e| AppError::Io(std::io::Error::other(e.to_string())))
+}
+
+fn set_node_link(db_path: &Path, token: &str, link: &str) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    let rows = conn.execute("UPDATE nodes SET link = ? WHERE token = ?", params![link, token])?;
+    if rows == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(())
+}
+
+/// 向在线的 Agent 下发消息，消息须为带 `type` 字段的对象。
+async fn send_agent_message_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+    Json(payload): Json<Value>,
 ) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    if payload.get("type").and_then(Value::as_str).is_none() {
+        return Err(AppError::BadRequest("type required".into()));
+    }
+    if !state.agents.send(&token, payload) {
+        return Err(AppError::Conflict("agent not connected via websocket".into()));
+    }
+    Ok(Json(json!({"status": "sent"})))
+}
+
+fn ingest_report(db_path: &Path, payload: &ReportPayload, addr: SocketAddr) -> Result<(), AppError> {
     if payload.meta.is_empty() || payload.metrics.is_empty() {
         return Err(AppError::BadRequest("meta/metrics required".into()));
     }
@@ -351,14 +832,114 @@ async fn report_handler(
         .clone()
         .unwrap_or_else(|| addr.ip().to_string());
     update_node_metrics(
-        &state.data_dir.join("imonitor.db"),
+        db_path,
         &payload.token,
         &payload.hostname,
         &client_ip,
         &payload.meta,
         &payload.metrics,
-    )?;
-    Ok(Json(json!({"status": "ok"})))
+        payload.timestamp,
+    )
+}
+
+/// 上报接口支持的编码，三者的逻辑结构完全一致。
+#[derive(Clone, Copy)]
+enum WireFormat {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl WireFormat {
+    fn from_mime(value: &str) -> Option<Self> {
+        let essence = value.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        match essence.as_str() {
+            "application/json" => Some(Self::Json),
+            "application/cbor" => Some(Self::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Self::MsgPack)
+            }
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+            Self::MsgPack => "application/msgpack",
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, raw: &[u8]) -> Result<T, AppError> {
+        let invalid = |e: String| AppError::BadRequest(format!("invalid payload: {e}"));
+        match self {
+            Self::Json => serde_json::from_slice(raw).map_err(|e| invalid(e.to_string())),
+            Self::Cbor => ciborium::from_reader(raw).map_err(|e| invalid(e.to_string())),
+            Self::MsgPack => rmp_serde::from_slice(raw).map_err(|e| invalid(e.to_string())),
+        }
+    }
+
+    fn encode(self, value: &Value) -> Result<Vec<u8>, AppError> {
+        let failed = |e: String| AppError::Io(std::io::Error::other(e));
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(|e| failed(e.to_string()))?;
+                Ok(buf)
+            }
+            Self::MsgPack => rmp_serde::to_vec(value).map_err(|e| failed(e.to_string())),
+        }
+    }
+}
+
+/// 按 Content-Encoding 解压后按 Content-Type 解码，未带 Content-Type 时按 JSON 处理。
+fn decode_report<T: DeserializeOwned>(headers: &HeaderMap, body: &[u8]) -> Result<T, AppError> {
+    let format = match headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        None => WireFormat::Json,
+        Some(ct) => WireFormat::from_mime(ct).ok_or_else(|| AppError::UnsupportedMediaType(ct.to_string()))?,
+    };
+    let raw = decode_body(headers, body)?;
+    format.decode(&raw)
+}
+
+/// 响应编码取 Accept 中第一个支持的类型，默认 JSON。
+fn encode_reply(headers: &HeaderMap, value: &Value) -> Result<Response, AppError> {
+    let format = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|accept| accept.split(',').find_map(WireFormat::from_mime))
+        .unwrap_or(WireFormat::Json);
+    let body = format.encode(value)?;
+    Ok(([(header::CONTENT_TYPE, format.content_type())], body).into_response())
+}
+
+// 解压后的上限，防止压缩炸弹
+const MAX_DECODED_BODY: u64 = 64 * 1024 * 1024;
+
+fn decode_body(headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>, AppError> {
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("identity")
+        .trim()
+        .to_ascii_lowercase();
+    let mut out = Vec::new();
+    let result = match encoding.as_str() {
+        "identity" | "" => return Ok(body.to_vec()),
+        "gzip" | "x-gzip" => flate2::read::GzDecoder::new(body)
+            .take(MAX_DECODED_BODY + 1)
+            .read_to_end(&mut out),
+        "zstd" => zstd::stream::read::Decoder::new(body)
+            .and_then(|d| d.take(MAX_DECODED_BODY + 1).read_to_end(&mut out)),
+        other => return Err(AppError::BadRequest(format!("unsupported encoding: {other}"))),
+    };
+    result.map_err(|e| AppError::BadRequest(format!("failed to decode body: {e}")))?;
+    if out.len() as u64 > MAX_DECODED_BODY {
+        return Err(AppError::BadRequest("decoded body too large".into()));
+    }
+    Ok(out)
 }
 
 async fn delete_node_handler(
@@ -374,6 +955,8 @@ async fn delete_node_handler(
 #[derive(Deserialize)]
 struct UpdateNodeRequest {
     label: Option<String>,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
 }
 
 async fn update_node_handler(
@@ -383,99 +966,416 @@ async fn update_node_handler(
     Json(payload): Json<UpdateNodeRequest>,
 ) -> Result<Json<Value>, AppError> {
     require_auth(&headers, &state.settings)?;
-    update_node_label(&state.data_dir.join("imonitor.db"), &token, payload.label.as_deref())?;
+    let db_path = state.data_dir.join("imonitor.db");
+    // 只改标签时保留原有名称；未带 tags 时沿用原语义（label 为空即清除）
+    if payload.label.is_some() || payload.tags.is_none() {
+        update_node_label(&db_path, &token, payload.label.as_deref())?;
+    }
+    if let Some(tags) = payload.tags.as_ref() {
+        update_node_tags(&db_path, &token, tags)?;
+    }
     Ok(Json(json!({"status": "updated"})))
 }
 
-async fn login_handler(
+async fn get_traffic_handler(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+) -> Result<Json<Value>, AppError> {
+    let conn = Connection::open(state.data_dir.join("imonitor.db"))?;
+    let summary = traffic_summary(&conn, &token, unix_now())?.ok_or(AppError::NotFound)?;
+    let history = traffic_history(&conn, &token)?;
+    Ok(Json(json!({ "current": summary, "history": history })))
+}
+
+async fn update_traffic_handler(
     State(state): State<AppState>,
     headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+    Json(payload): Json<UpdateTrafficRequest>,
 ) -> Result<Json<Value>, AppError> {
     require_auth(&headers, &state.settings)?;
-    Ok(Json(json!({"status": "ok"})))
+    update_traffic_settings(&state.data_dir.join("imonitor.db"), &token, &payload)?;
+    Ok(Json(json!({"status": "updated"})))
 }
 
-struct NewNode {
-    node_id: String,
-    token: String,
+#[derive(Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    hours: Option<f64>,
 }
 
-fn init_db(db_path: &Path) -> Result<(), AppError> {
-    let conn = Connection::open(db_path)?;
-    conn.execute_batch(
-        "CREATE TABLE IF NOT EXISTS nodes (
-            id TEXT PRIMARY KEY,
-            token TEXT UNIQUE NOT NULL,
-            label TEXT,
-            hostname TEXT,
-            ip_address TEXT,
-            created_at REAL DEFAULT (strftime('%s','now')),
-            last_seen REAL,
-            meta TEXT,
-            metrics TEXT
-        );
-        CREATE INDEX IF NOT EXISTS idx_nodes_token ON nodes(token);
-        ",
-    )?;
-    Ok(())
+async fn get_history_handler(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Value>, AppError> {
+    let hours = query.hours.unwrap_or(24.0).clamp(0.0, HISTORY_RETENTION_SECS / 3600.0);
+    let points = load_history(&state.data_dir.join("imonitor.db"), &token, unix_now() - hours * 3600.0)?;
+    Ok(Json(json!({ "points": points })))
 }
 
-fn list_nodes(db_path: &Path, offline_timeout: u64) -> Result<Vec<NodeResponse>, AppError> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT * FROM nodes ORDER BY created_at ASC")?;
-    let rows = stmt.query_map([], |row| {
-        Ok(NodeRaw {
-            id: row.get("id")?,
-            label: row.get("label")?,
-            hostname: row.get("hostname")?,
-            ip_address: row.get("ip_address")?,
-            created_at: row.get("created_at")?,
-            last_seen: row.get("last_seen")?,
-            token: row.get("token")?,
-            meta: row.get("meta")?,
-            metrics: row.get("metrics")?,
-        })
-    })?;
-    let mut result = Vec::new();
-    for row in rows {
-        result.push(row?.into_response(offline_timeout)?);
-    }
-    Ok(result)
+#[derive(Deserialize)]
+struct CustomHistoryQuery {
+    hours: Option<f64>,
+    name: Option<String>,
 }
 
-fn create_node(db_path: &Path, label: Option<&str>) -> Result<NewNode, AppError> {
-    let conn = Connection::open(db_path)?;
-    let node_id = Uuid::new_v4().to_string();
-    let token = generate_token();
-    conn.execute(
-        "INSERT INTO nodes (id, token, label, created_at) VALUES (?, ?, ?, strftime('%s','now'))",
-        params![node_id, token, label],
+async fn get_custom_history_handler(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+    Query(query): Query<CustomHistoryQuery>,
+) -> Result<Json<Value>, AppError> {
+    let hours = query.hours.unwrap_or(24.0).clamp(0.0, HISTORY_RETENTION_SECS / 3600.0);
+    let series = load_custom_history(
+        &state.data_dir.join("imonitor.db"),
+        &token,
+        unix_now() - hours * 3600.0,
+        query.name.as_deref(),
     )?;
-    Ok(NewNode { node_id, token })
+    Ok(Json(json!({ "series": series })))
 }
 
-fn update_node_metrics(
-    db_path: &Path,
-    token: &str,
-    hostname: &str,
-    ip_address: &str,
-    meta: &Map<String, Value>,
-    metrics: &Map<String, Value>,
-) -> Result<(), AppError> {
-    let conn = Connection::open(db_path)?;
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs_f64();
-    let meta_json = serde_json::to_string(meta)?;
-    let metrics_json = serde_json::to_string(metrics)?;
+async fn list_node_events_handler(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+) -> Result<Json<Value>, AppError> {
+    let events = list_node_events(&state.data_dir.join("imonitor.db"), &token)?;
+    Ok(Json(json!({ "events": events })))
+}
+
+async fn list_agent_configs_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let conn = Connection::open(state.data_dir.join("imonitor.db"))?;
+    let configs = load_agent_configs(&conn)?;
+    Ok(Json(json!({ "configs": configs })))
+}
+
+async fn put_agent_config_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<PutAgentConfigRequest>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let db_path = state.data_dir.join("imonitor.db");
+    let id = put_agent_config(&db_path, &payload)?;
+    push_agent_configs(&state, &db_path);
+    Ok(Json(json!({"status": "saved", "id": id})))
+}
+
+async fn delete_agent_config_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxumPath(id): AxumPath<String>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let db_path = state.data_dir.join("imonitor.db");
+    let conn = Connection::open(&db_path)?;
+    let rows = conn.execute("DELETE FROM agent_configs WHERE id = ?", params![id])?;
+    if rows == 0 {
+        return Err(AppError::NotFound);
+    }
+    push_agent_configs(&state, &db_path);
+    Ok(Json(json!({"status": "deleted"})))
+}
+
+async fn get_node_agent_config_handler(
+    State(state): State<AppState>,
+    AxumPath(token): AxumPath<String>,
+) -> Result<Json<Value>, AppError> {
+    Ok(Json(node_agent_config(&state.data_dir.join("imonitor.db"), &token)?))
+}
+
+/// Agent 以 `Authorization: Bearer <token>` 查询自身生效的配置，令牌不出现在 URL 与访问日志中。
+async fn agent_config_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AppError> {
+    let token = bearer_token(&headers).ok_or(AppError::Unauthorized)?;
+    Ok(Json(node_agent_config(&state.data_dir.join("imonitor.db"), &token)?))
+}
+
+fn node_agent_config(db_path: &Path, token: &str) -> Result<Value, AppError> {
+    let conn = Connection::open(db_path)?;
+    let exists: bool = conn
+        .query_row("SELECT 1 FROM nodes WHERE token = ?", params![token], |_| Ok(true))
+        .unwrap_or(false);
+    if !exists {
+        return Err(AppError::NotFound);
+    }
+    let (version, settings) = effective_agent_config(&conn, token)?;
+    Ok(json!({ "version": version, "settings": settings }))
+}
+
+/// 配置变更后立即推送给通过 WebSocket 在线的 Agent，其余 Agent 在下次上报时获取。
+fn push_agent_configs(state: &AppState, db_path: &Path) {
+    let conn = match Connection::open(db_path) {
+        Ok(conn) => conn,
+        Err(err) => {
+            warn!("failed to push agent configs: {err}");
+            return;
+        }
+    };
+    for token in state.agents.tokens() {
+        match effective_agent_config(&conn, &token) {
+            Ok((version, settings)) => {
+                state.agents.send(
+                    &token,
+                    json!({"type": "config", "version": version, "settings": settings}),
+                );
+            }
+            Err(err) => warn!("failed to build agent config for {token}: {err}"),
+        }
+    }
+}
+
+async fn list_watches_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let conn = Connection::open(state.data_dir.join("imonitor.db"))?;
+    let watches = load_watches(&conn)?;
+    Ok(Json(json!({ "watches": watches })))
+}
+
+async fn create_watch_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateWatchRequest>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    let id = create_watch(&state.data_dir.join("imonitor.db"), &payload)?;
+    Ok(Json(json!({"status": "created", "id": id})))
+}
+
+async fn delete_watch_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxumPath(id): AxumPath<String>,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    delete_watch(&state.data_dir.join("imonitor.db"), &id)?;
+    Ok(Json(json!({"status": "deleted"})))
+}
+
+async fn login_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AppError> {
+    require_auth(&headers, &state.settings)?;
+    Ok(Json(json!({"status": "ok"})))
+}
+
+struct NewNode {
+    node_id: String,
+    token: String,
+}
+
+fn init_db(db_path: &Path) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS nodes (
+            id TEXT PRIMARY KEY,
+            token TEXT UNIQUE NOT NULL,
+            label TEXT,
+            hostname TEXT,
+            ip_address TEXT,
+            created_at REAL DEFAULT (strftime('%s','now')),
+            last_seen REAL,
+            meta TEXT,
+            metrics TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_nodes_token ON nodes(token);
+        CREATE TABLE IF NOT EXISTS alert_rules (
+            id TEXT PRIMARY KEY,
+            node_token TEXT,
+            metric TEXT NOT NULL,
+            target TEXT,
+            op TEXT NOT NULL DEFAULT '>',
+            threshold REAL NOT NULL,
+            created_at REAL DEFAULT (strftime('%s','now'))
+        );
+        CREATE TABLE IF NOT EXISTS alert_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            rule_id TEXT NOT NULL,
+            node_token TEXT NOT NULL,
+            metric TEXT NOT NULL,
+            target TEXT,
+            value REAL,
+            threshold REAL NOT NULL,
+            fired_at REAL NOT NULL,
+            resolved_at REAL
+        );
+        CREATE INDEX IF NOT EXISTS idx_alert_events_open ON alert_events(rule_id, node_token, resolved_at);
+        CREATE TABLE IF NOT EXISTS traffic_state (
+            node_token TEXT PRIMARY KEY,
+            reset_day INTEGER NOT NULL DEFAULT 1,
+            quota_bytes INTEGER,
+            quota_mode TEXT NOT NULL DEFAULT 'sum',
+            last_sent INTEGER,
+            last_recv INTEGER,
+            last_uptime INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS watches (
+            id TEXT PRIMARY KEY,
+            node_token TEXT,
+            tag TEXT,
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL,
+            cmdline TEXT,
+            pidfile TEXT,
+            created_at REAL DEFAULT (strftime('%s','now'))
+        );
+        CREATE TABLE IF NOT EXISTS listen_ports (
+            node_token TEXT NOT NULL,
+            proto TEXT NOT NULL,
+            address TEXT NOT NULL,
+            port INTEGER NOT NULL,
+            process TEXT,
+            first_seen REAL NOT NULL,
+            PRIMARY KEY (node_token, proto, address, port)
+        );
+        CREATE TABLE IF NOT EXISTS node_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            node_token TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            detail TEXT,
+            created_at REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_node_events_token ON node_events(node_token, created_at);
+        CREATE TABLE IF NOT EXISTS metrics_history (
+            node_token TEXT NOT NULL,
+            ts REAL NOT NULL,
+            cpu REAL,
+            memory REAL,
+            disk REAL,
+            net_in REAL,
+            net_out REAL,
+            load1 REAL,
+            PRIMARY KEY (node_token, ts)
+        );
+        CREATE INDEX IF NOT EXISTS idx_metrics_history_ts ON metrics_history(ts);
+        CREATE TABLE IF NOT EXISTS custom_history (
+            node_token TEXT NOT NULL,
+            name TEXT NOT NULL,
+            ts REAL NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (node_token, name, ts)
+        );
+        CREATE INDEX IF NOT EXISTS idx_custom_history_ts ON custom_history(ts);
+        CREATE TABLE IF NOT EXISTS agent_configs (
+            id TEXT PRIMARY KEY,
+            node_token TEXT,
+            tag TEXT,
+            settings TEXT NOT NULL,
+            updated_at REAL NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS traffic_monthly (
+            node_token TEXT NOT NULL,
+            period TEXT NOT NULL,
+            sent INTEGER NOT NULL DEFAULT 0,
+            recv INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (node_token, period)
+        );
+        ",
+    )?;
+    ensure_column(&conn, "nodes", "tags", "TEXT")?;
+    ensure_column(&conn, "nodes", "ports_baseline_at", "REAL")?;
+    // last_seen 为面板收到数据的时间，sampled_at 为当前 metrics 的采集时间
+    ensure_column(&conn, "nodes", "sampled_at", "REAL")?;
+    // ws：WebSocket 在线；closed：WebSocket 已断开；NULL：HTTP 上报
+    ensure_column(&conn, "nodes", "link", "TEXT")?;
+    // 端口首次从上报中消失的时间，连续两次缺失才记为关闭
+    ensure_column(&conn, "listen_ports", "missing_since", "REAL")?;
+    // 自定义指标按分钟聚合，samples 为该分钟内已合并的上报次数
+    ensure_column(&conn, "custom_history", "samples", "INTEGER NOT NULL DEFAULT 1")?;
+    Ok(())
+}
+
+fn list_nodes(db_path: &Path, offline_timeout: u64) -> Result<Vec<NodeResponse>, AppError> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT * FROM nodes ORDER BY created_at ASC")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(NodeRaw {
+            id: row.get("id")?,
+            label: row.get("label")?,
+            hostname: row.get("hostname")?,
+            ip_address: row.get("ip_address")?,
+            created_at: row.get("created_at")?,
+            last_seen: row.get("last_seen")?,
+            token: row.get("token")?,
+            meta: row.get("meta")?,
+            metrics: row.get("metrics")?,
+            tags: row.get("tags")?,
+            link: row.get("link")?,
+        })
+    })?;
+    let now = unix_now();
+    let mut result = Vec::new();
+    for row in rows {
+        let mut node = row?.into_response(offline_timeout)?;
+        node.traffic = traffic_summary(&conn, &node.token, now)?;
+        result.push(node);
+    }
+    Ok(result)
+}
+
+fn create_node(db_path: &Path, label: Option<&str>) -> Result<NewNode, AppError> {
+    let conn = Connection::open(db_path)?;
+    let node_id = Uuid::new_v4().to_string();
+    let token = generate_token();
+    conn.execute(
+        "INSERT INTO nodes (id, token, label, created_at) VALUES (?, ?, ?, strftime('%s','now'))",
+        params![node_id, token, label],
+    )?;
+    Ok(NewNode { node_id, token })
+}
+
+fn update_node_metrics(
+    db_path: &Path,
+    token: &str,
+    hostname: &str,
+    ip_address: &str,
+    meta: &Map<String, Value>,
+    metrics: &Map<String, Value>,
+    timestamp: Option<f64>,
+) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    let wall = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    // 不信任超前于面板时钟的时间戳
+    let now = timestamp.map_or(wall, |ts| ts.min(wall));
+    let sampled_at: Option<f64> = conn
+        .query_row(
+            "SELECT COALESCE(sampled_at, last_seen) FROM nodes WHERE token = ?",
+            params![token],
+            |row| row.get(0),
+        )
+        .map_err(|err| match err {
+            rusqlite::Error::QueryReturnedNoRows => AppError::NotFound,
+            other => other.into(),
+        })?;
+    record_history(&conn, token, metrics, now)?;
+    // 补传的旧样本只进入历史，不覆盖当前状态，也不参与流量与告警计算
+    if sampled_at.is_some_and(|seen| now < seen) {
+        return Ok(());
+    }
+    let meta_json = serde_json::to_string(meta)?;
+    let metrics_json = serde_json::to_string(metrics)?;
     let rows = conn.execute(
         "UPDATE nodes SET hostname = COALESCE(?, hostname),
             label = CASE WHEN label IS NULL OR label = '' THEN ? ELSE label END,
             ip_address = ?,
             meta = ?,
             metrics = ?,
-            last_seen = ?
+            last_seen = ?,
+            sampled_at = ?,
+            link = NULLIF(link, 'closed')
         WHERE token = ?",
         params![
             hostname,
@@ -483,6 +1383,7 @@ fn update_node_metrics(
             ip_address,
             meta_json,
             metrics_json,
+            wall,
             now,
             token
         ],
@@ -501,15 +1402,154 @@ fn update_node_metrics(
             params![token, hostname, ip_address],
         )?;
     }
+    let traffic = account_traffic(&conn, token, metrics, now)?;
+    let mut alert_metrics = metrics.clone();
+    if let Some(percent) = traffic.and_then(|t| t.usage_percent) {
+        alert_metrics.insert("traffic_percent".into(), json!(percent));
+    }
+    evaluate_alerts(&conn, token, &alert_metrics, now)?;
+    evaluate_watch_alerts(&conn, token, metrics, now)?;
+    track_listen_ports(&conn, token, metrics, now)?;
+    Ok(())
+}
+
+const HISTORY_RETENTION_SECS: f64 = 7.0 * 86400.0;
+const HISTORY_PRUNE_INTERVAL_SECS: u64 = 60;
+const CUSTOM_HISTORY_STEP_SECS: f64 = 60.0;
+/// 上次清理过期历史的时间（Unix 秒），所有节点共用一次清理。
+static LAST_HISTORY_PRUNE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize)]
+struct HistoryPoint {
+    ts: f64,
+    cpu: Option<f64>,
+    memory: Option<f64>,
+    disk: Option<f64>,
+    net_in: Option<f64>,
+    net_out: Option<f64>,
+    load1: Option<f64>,
+}
+
+fn record_history(conn: &Connection, token: &str, metrics: &Map<String, Value>, ts: f64) -> Result<(), AppError> {
+    let num = |key: &str| metrics.get(key).and_then(Value::as_f64);
+    let load1 = metrics
+        .get("load_avg")
+        .and_then(|v| v.get(0))
+        .and_then(Value::as_f64);
+    conn.execute(
+        "INSERT OR REPLACE INTO metrics_history (node_token, ts, cpu, memory, disk, net_in, net_out, load1)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            token,
+            ts,
+            num("cpu"),
+            num("memory_percent"),
+            num("disk_percent"),
+            num("net_recv_speed"),
+            num("net_sent_speed"),
+            load1
+        ],
+    )?;
+    // 自定义指标为扁平的 {名称: 数值}，来自 Agent 插件、textfile 与 StatsD；
+    // 指标数可达数百个，按分钟取平均存一行，避免每次上报都为每个指标写一行
+    if let Some(custom) = metrics.get("custom").and_then(Value::as_object) {
+        let minute = (ts / CUSTOM_HISTORY_STEP_SECS).floor() * CUSTOM_HISTORY_STEP_SECS;
+        let mut stmt = conn.prepare_cached(
+            "INSERT INTO custom_history (node_token, name, ts, value, samples) VALUES (?, ?, ?, ?, 1)
+            ON CONFLICT (node_token, name, ts) DO UPDATE SET
+                value = (value * samples + excluded.value) / (samples + 1),
+                samples = samples + 1",
+        )?;
+        for (name, value) in custom {
+            if let Some(value) = value.as_f64() {
+                stmt.execute(params![token, name, minute, value])?;
+            }
+        }
+    }
+    prune_history(conn, unix_now())
+}
+
+/// 过期历史每分钟最多清理一次，避免每次上报都执行 DELETE。
+fn prune_history(conn: &Connection, now: f64) -> Result<(), AppError> {
+    let now_secs = now as u64;
+    let last = LAST_HISTORY_PRUNE.load(Ordering::Relaxed);
+    if now_secs < last + HISTORY_PRUNE_INTERVAL_SECS
+        || LAST_HISTORY_PRUNE
+            .compare_exchange(last, now_secs, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return Ok(());
+    }
+    let cutoff = now - HISTORY_RETENTION_SECS;
+    conn.execute("DELETE FROM metrics_history WHERE ts < ?", params![cutoff])?;
+    conn.execute("DELETE FROM custom_history WHERE ts < ?", params![cutoff])?;
     Ok(())
 }
 
+/// 按名称分组的自定义指标序列，每个点为 [时间戳, 数值]。
+fn load_custom_history(
+    db_path: &Path,
+    token: &str,
+    since: f64,
+    name: Option<&str>,
+) -> Result<Map<String, Value>, AppError> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT name, ts, value FROM custom_history
+        WHERE node_token = ?1 AND ts >= ?2 AND (?3 IS NULL OR name = ?3)
+        ORDER BY name, ts ASC",
+    )?;
+    let rows = stmt.query_map(params![token, since, name], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?))
+    })?;
+    let mut series = Map::new();
+    for row in rows {
+        let (name, ts, value) = row?;
+        if let Value::Array(points) = series.entry(name).or_insert_with(|| Value::Array(Vec::new())) {
+            points.push(json!([ts, value]));
+        }
+    }
+    Ok(series)
+}
+
+fn load_history(db_path: &Path, token: &str, since: f64) -> Result<Vec<HistoryPoint>, AppError> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT * FROM metrics_history WHERE node_token = ? AND ts >= ? ORDER BY ts ASC",
+    )?;
+    let rows = stmt.query_map(params![token, since], |row| {
+        Ok(HistoryPoint {
+            ts: row.get("ts")?,
+            cpu: row.get("cpu")?,
+            memory: row.get("memory")?,
+            disk: row.get("disk")?,
+            net_in: row.get("net_in")?,
+            net_out: row.get("net_out")?,
+            load1: row.get("load1")?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
 fn delete_node(db_path: &Path, token: &str) -> Result<(), AppError> {
     let conn = Connection::open(db_path)?;
     let rows = conn.execute("DELETE FROM nodes WHERE token = ?", params![token])?;
     if rows == 0 {
         return Err(AppError::NotFound);
     }
+    conn.execute("DELETE FROM alert_rules WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM traffic_state WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM traffic_monthly WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM watches WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM listen_ports WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM node_events WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM metrics_history WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM custom_history WHERE node_token = ?", params![token])?;
+    conn.execute("DELETE FROM agent_configs WHERE node_token = ?", params![token])?;
     Ok(())
 }
 
@@ -525,6 +1565,705 @@ fn update_node_label(db_path: &Path, token: &str, label: Option<&str>) -> Result
     Ok(())
 }
 
+fn update_node_tags(db_path: &Path, token: &str, tags: &[String]) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    let mut clean: Vec<String> = tags
+        .iter()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+    clean.sort();
+    clean.dedup();
+    let rows = conn.execute(
+        "UPDATE nodes SET tags = ? WHERE token = ?",
+        params![serde_json::to_string(&clean)?, token],
+    )?;
+    if rows == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(())
+}
+
+/// 旧库没有新增的列时补上，CREATE TABLE IF NOT EXISTS 不会修改已有表结构。
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<(), AppError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Watch {
+    id: String,
+    node_token: Option<String>,
+    tag: Option<String>,
+    kind: String,
+    name: String,
+    cmdline: Option<String>,
+    pidfile: Option<String>,
+    created_at: f64,
+}
+
+#[derive(Deserialize)]
+struct CreateWatchRequest {
+    #[serde(default)]
+    node_token: Option<String>,
+    #[serde(default)]
+    tag: Option<String>,
+    kind: String,
+    name: String,
+    #[serde(default)]
+    cmdline: Option<String>,
+    #[serde(default)]
+    pidfile: Option<String>,
+}
+
+fn load_watches(conn: &Connection) -> Result<Vec<Watch>, AppError> {
+    let mut stmt = conn.prepare("SELECT * FROM watches ORDER BY created_at ASC")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Watch {
+            id: row.get("id")?,
+            node_token: row.get("node_token")?,
+            tag: row.get("tag")?,
+            kind: row.get("kind")?,
+            name: row.get("name")?,
+            cmdline: row.get("cmdline")?,
+            pidfile: row.get("pidfile")?,
+            created_at: row.get("created_at")?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+fn node_tags(conn: &Connection, token: &str) -> Vec<String> {
+    conn.query_row("SELECT tags FROM nodes WHERE token = ?", params![token], |row| {
+        row.get::<_, Option<String>>(0)
+    })
+    .ok()
+    .flatten()
+    .and_then(|t| serde_json::from_str(&t).ok())
+    .unwrap_or_default()
+}
+
+/// 节点适用的监视项：指定该节点、指定其任一标签，或两者都未指定（全局）。
+fn watches_for_node(conn: &Connection, token: &str) -> Result<Vec<Value>, AppError> {
+    let tags = node_tags(conn, token);
+    let watches = load_watches(conn)?
+        .into_iter()
+        .filter(|w| match (&w.node_token, &w.tag) {
+            (Some(t), _) => t == token,
+            (None, Some(tag)) => tags.contains(tag),
+            (None, None) => true,
+        })
+        .map(|w| {
+            json!({
+                "id": w.id,
+                "kind": w.kind,
+                "name": w.name,
+                "cmdline": w.cmdline,
+                "pidfile": w.pidfile,
+            })
+        })
+        .collect();
+    Ok(watches)
+}
+
+fn create_watch(db_path: &Path, req: &CreateWatchRequest) -> Result<String, AppError> {
+    if req.name.trim().is_empty() {
+        return Err(AppError::BadRequest("name required".into()));
+    }
+    if req.kind != "process" && req.kind != "systemd" {
+        return Err(AppError::BadRequest("kind must be process or systemd".into()));
+    }
+    let conn = Connection::open(db_path)?;
+    let id = Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO watches (id, node_token, tag, kind, name, cmdline, pidfile, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, strftime('%s','now'))",
+        params![id, req.node_token, req.tag, req.kind, req.name.trim(), req.cmdline, req.pidfile],
+    )?;
+    Ok(id)
+}
+
+fn delete_watch(db_path: &Path, id: &str) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    let rows = conn.execute("DELETE FROM watches WHERE id = ?", params![id])?;
+    if rows == 0 {
+        return Err(AppError::NotFound);
+    }
+    conn.execute(
+        "UPDATE alert_events SET resolved_at = strftime('%s','now') WHERE rule_id = ? AND resolved_at IS NULL",
+        params![format!("watch:{id}")],
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct NodeEvent {
+    id: i64,
+    kind: String,
+    detail: Option<Value>,
+    created_at: f64,
+}
+
+/// 对比上报的监听端口与上次记录的集合，新增或消失的端口记为节点事件。
+/// 节点第一次上报时只建立基线，不产生事件。
+/// (proto, address, port, process, missing_since)
+type KnownPort = (String, String, i64, Option<String>, Option<f64>);
+
+fn track_listen_ports(
+    conn: &Connection,
+    token: &str,
+    metrics: &Map<String, Value>,
+    now: f64,
+) -> Result<(), AppError> {
+    // 旧版 Agent 不上报该字段
+    let items = match metrics.get("listening").and_then(Value::as_array) {
+        Some(items) => items,
+        None => return Ok(()),
+    };
+    let mut current = Vec::new();
+    for item in items {
+        let (Some(proto), Some(address), Some(port)) = (
+            item.get("proto").and_then(Value::as_str),
+            item.get("address").and_then(Value::as_str),
+            item.get("port").and_then(Value::as_i64),
+        ) else {
+            continue;
+        };
+        let process = item.get("process").and_then(Value::as_str);
+        current.push((proto, address, port, process));
+    }
+    let mut stmt = conn.prepare(
+        "SELECT proto, address, port, process, missing_since FROM listen_ports WHERE node_token = ?",
+    )?;
+    let known: Vec<KnownPort> = stmt
+        .query_map(params![token], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    let baseline = conn
+        .query_row(
+            "SELECT ports_baseline_at FROM nodes WHERE token = ?",
+            params![token],
+            |row| row.get::<_, Option<f64>>(0),
+        )?
+        .is_none();
+    if baseline {
+        conn.execute("UPDATE nodes SET ports_baseline_at = ? WHERE token = ?", params![now, token])?;
+    }
+    // 事件按 (协议, 端口) 计：同一端口换绑地址不算开关，多个地址只记一次
+    let mut opened: Vec<(&str, i64)> = Vec::new();
+    for &(proto, address, port, process) in &current {
+        if known.iter().any(|(p, a, n, _, _)| p == proto && a == address && *n == port) {
+            continue;
+        }
+        conn.execute(
+            "INSERT INTO listen_ports (node_token, proto, address, port, process, first_seen)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            params![token, proto, address, port, process, now],
+        )?;
+        let port_known = known.iter().any(|(p, _, n, _, _)| p == proto && *n == port);
+        if !baseline && !port_known && !opened.contains(&(proto, port)) {
+            opened.push((proto, port));
+            let detail = json!({"proto": proto, "address": address, "port": port, "process": process});
+            record_node_event(conn, token, "port_opened", &detail, now)?;
+        }
+    }
+    let mut closed: Vec<(&str, i64)> = Vec::new();
+    for (proto, address, port, process, missing_since) in &known {
+        let key = (proto.as_str(), *port);
+        let row = params![token, proto, address, port];
+        if current.iter().any(|(p, a, n, _)| p == proto && a == address && n == port) {
+            if missing_since.is_some() {
+                conn.execute(
+                    "UPDATE listen_ports SET missing_since = NULL
+                    WHERE node_token = ? AND proto = ? AND address = ? AND port = ?",
+                    row,
+                )?;
+            }
+            continue;
+        }
+        let port_open = current.iter().any(|(p, _, n, _)| p == proto && n == port);
+        // 服务重启时端口可能恰好在一次上报中缺席，首次缺失只做标记
+        if !port_open && missing_since.is_none() {
+            conn.execute(
+                "UPDATE listen_ports SET missing_since = ?
+                WHERE node_token = ? AND proto = ? AND address = ? AND port = ?",
+                params![now, token, proto, address, port],
+            )?;
+            continue;
+        }
+        conn.execute(
+            "DELETE FROM listen_ports WHERE node_token = ? AND proto = ? AND address = ? AND port = ?",
+            row,
+        )?;
+        if !port_open && !closed.contains(&key) {
+            closed.push(key);
+            let detail = json!({"proto": proto, "address": address, "port": port, "process": process});
+            record_node_event(conn, token, "port_closed", &detail, now)?;
+        }
+    }
+    Ok(())
+}
+
+fn record_node_event(conn: &Connection, token: &str, kind: &str, detail: &Value, now: f64) -> Result<(), AppError> {
+    info!("node {token} event {kind}: {detail}");
+    conn.execute(
+        "INSERT INTO node_events (node_token, kind, detail, created_at) VALUES (?, ?, ?, ?)",
+        params![token, kind, serde_json::to_string(detail)?, now],
+    )?;
+    Ok(())
+}
+
+fn list_node_events(db_path: &Path, token: &str) -> Result<Vec<NodeEvent>, AppError> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT * FROM node_events WHERE node_token = ? ORDER BY created_at DESC, id DESC LIMIT 200",
+    )?;
+    let rows = stmt.query_map(params![token], |row| {
+        Ok(NodeEvent {
+            id: row.get("id")?,
+            kind: row.get("kind")?,
+            detail: row
+                .get::<_, Option<String>>("detail")?
+                .and_then(|d| serde_json::from_str(&d).ok()),
+            created_at: row.get("created_at")?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+#[derive(Serialize)]
+struct AgentConfigRow {
+    id: String,
+    node_token: Option<String>,
+    tag: Option<String>,
+    settings: Value,
+    updated_at: f64,
+}
+
+#[derive(Deserialize)]
+struct PutAgentConfigRequest {
+    #[serde(default)]
+    node_token: Option<String>,
+    #[serde(default)]
+    tag: Option<String>,
+    settings: Map<String, Value>,
+}
+
+/// 可由面板下发的采集项，对应 `collectors` 中的键。
+const AGENT_COLLECTORS: &[&str] = &[
+    "mounts", "disk_io", "pressure", "temperatures", "processes", "sockets", "listening",
+    "conntrack", "cgroup", "systemd", "containers", "custom",
+];
+
+fn validate_agent_settings(settings: &Map<String, Value>) -> Result<(), AppError> {
+    let bad = |key: &str, expect: &str| Err(AppError::BadRequest(format!("{key} must be {expect}")));
+    for (key, value) in settings {
+        match key.as_str() {
+            "interval" | "top_n" => {
+                if value.as_u64().filter(|v| *v >= 1).is_none() {
+                    return bad(key, "a positive integer");
+                }
+            }
+            "flag" => {
+                if !value.is_string() {
+                    return bad(key, "a string");
+                }
+            }
+            "skip_fs" | "net_include" | "net_exclude" | "disk_exclude" => {
+                let ok = value.as_array().is_some_and(|items| items.iter().all(Value::is_string));
+                if !ok {
+                    return bad(key, "an array of strings");
+                }
+            }
+            "collectors" => {
+                let Some(map) = value.as_object() else {
+                    return bad(key, "an object");
+                };
+                for (name, enabled) in map {
+                    if !AGENT_COLLECTORS.contains(&name.as_str()) || !enabled.is_boolean() {
+                        return Err(AppError::BadRequest(format!("unknown collector or non-boolean value: {name}")));
+                    }
+                }
+            }
+            other => return Err(AppError::BadRequest(format!("unknown setting: {other}"))),
+        }
+    }
+    Ok(())
+}
+
+fn load_agent_configs(conn: &Connection) -> Result<Vec<AgentConfigRow>, AppError> {
+    let mut stmt = conn.prepare("SELECT * FROM agent_configs ORDER BY updated_at ASC")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(AgentConfigRow {
+            id: row.get("id")?,
+            node_token: row.get("node_token")?,
+            tag: row.get("tag")?,
+            settings: serde_json::from_str(&row.get::<_, String>("settings")?).unwrap_or(Value::Null),
+            updated_at: row.get("updated_at")?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+/// 每个作用域（全局、某标签、某节点）只保留一份配置，重复提交即覆盖。
+fn put_agent_config(db_path: &Path, req: &PutAgentConfigRequest) -> Result<String, AppError> {
+    if req.node_token.is_some() && req.tag.is_some() {
+        return Err(AppError::BadRequest("node_token and tag are exclusive".into()));
+    }
+    validate_agent_settings(&req.settings)?;
+    let conn = Connection::open(db_path)?;
+    let settings = serde_json::to_string(&req.settings)?;
+    let existing: Option<String> = conn
+        .query_row(
+            "SELECT id FROM agent_configs WHERE node_token IS ? AND tag IS ?",
+            params![req.node_token, req.tag],
+            |row| row.get(0),
+        )
+        .ok();
+    let id = existing.unwrap_or_else(|| Uuid::new_v4().to_string());
+    conn.execute(
+        "INSERT OR REPLACE INTO agent_configs (id, node_token, tag, settings, updated_at)
+        VALUES (?, ?, ?, ?, ?)",
+        params![id, req.node_token, req.tag, settings, unix_now()],
+    )?;
+    Ok(id)
+}
+
+/// 按 全局 < 标签（按名称排序）< 节点 的顺序合并，`collectors` 逐项合并。
+/// 版本为合并结果的哈希，没有任何配置时为空字符串。
+fn effective_agent_config(conn: &Connection, token: &str) -> Result<(String, Map<String, Value>), AppError> {
+    let tags = node_tags(conn, token);
+    let mut rows: Vec<(u8, String, Map<String, Value>)> = load_agent_configs(conn)?
+        .into_iter()
+        .filter_map(|row| {
+            let rank = match (&row.node_token, &row.tag) {
+                (Some(t), _) if t == token => (2, String::new()),
+                (None, Some(tag)) if tags.contains(tag) => (1, tag.clone()),
+                (None, None) => (0, String::new()),
+                _ => return None,
+            };
+            Some((rank.0, rank.1, row.settings.as_object().cloned().unwrap_or_default()))
+        })
+        .collect();
+    rows.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    let mut merged = Map::new();
+    for (_, _, settings) in rows {
+        for (key, value) in settings {
+            match (merged.get_mut(&key), value) {
+                (Some(Value::Object(current)), Value::Object(extra)) if key == "collectors" => {
+                    current.extend(extra);
+                }
+                (_, value) => {
+                    merged.insert(key, value);
+                }
+            }
+        }
+    }
+    if merged.is_empty() {
+        return Ok((String::new(), merged));
+    }
+    // Map 按键有序，序列化结果即规范形式；版本号需跨面板升级保持稳定，不能用 DefaultHasher
+    let digest = Sha256::digest(serde_json::to_vec(&merged)?);
+    let version = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+    Ok((version, merged))
+}
+
+/// Agent 上报的配置版本与当前不一致时在响应中附带新配置；旧版 Agent 不带版本，不下发。
+fn attach_agent_config(conn: &Connection, payload: &ReportPayload, reply: &mut Value) -> Result<(), AppError> {
+    let Some(current) = payload.config_version.as_deref() else {
+        return Ok(());
+    };
+    let (version, settings) = effective_agent_config(conn, &payload.token)?;
+    if version != current {
+        reply["config"] = json!({"version": version, "settings": settings});
+    }
+    Ok(())
+}
+
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+#[derive(Serialize)]
+struct TrafficSummary {
+    reset_day: u32,
+    quota_mode: String,
+    quota_bytes: Option<i64>,
+    cycle_start: f64,
+    cycle_end: f64,
+    sent: i64,
+    recv: i64,
+    used: i64,
+    usage_percent: Option<f64>,
+    projected: i64,
+    projected_percent: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct TrafficPeriod {
+    period: String,
+    sent: i64,
+    recv: i64,
+}
+
+#[derive(Deserialize)]
+struct UpdateTrafficRequest {
+    #[serde(default)]
+    reset_day: Option<u32>,
+    /// 传 0 或负数表示取消配额
+    #[serde(default)]
+    quota_gb: Option<f64>,
+    #[serde(default)]
+    quota_mode: Option<String>,
+}
+
+fn metric_bytes(metrics: &Map<String, Value>, bytes_key: &str, gb_key: &str) -> Option<u64> {
+    // 旧版 Agent 只上报以 GB 为单位的累计值
+    metrics
+        .get(bytes_key)
+        .and_then(Value::as_u64)
+        .or_else(|| metrics.get(gb_key).and_then(Value::as_f64).map(|v| (v * GB) as u64))
+}
+
+/// 按 UTC 日期计算账单周期 `[start, end)`，reset_day 限定为 1..=28 以避开短月。
+fn billing_cycle(now: f64, reset_day: u32) -> (f64, f64) {
+    let day = reset_day.clamp(1, 28);
+    let (y, m, d) = civil_from_days((now / 86400.0).floor() as i64);
+    let (sy, sm) = if d >= day { (y, m) } else { prev_month(y, m) };
+    let (ey, em) = next_month(sy, sm);
+    (
+        days_from_civil(sy, sm, day) as f64 * 86400.0,
+        days_from_civil(ey, em, day) as f64 * 86400.0,
+    )
+}
+
+fn prev_month(y: i64, m: u32) -> (i64, u32) {
+    if m == 1 {
+        (y - 1, 12)
+    } else {
+        (y, m - 1)
+    }
+}
+
+fn next_month(y: i64, m: u32) -> (i64, u32) {
+    if m == 12 {
+        (y + 1, 1)
+    } else {
+        (y, m + 1)
+    }
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+fn period_key(cycle_start: f64) -> String {
+    let (y, m, d) = civil_from_days((cycle_start / 86400.0).floor() as i64);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// 根据 Agent 上报的累计计数器累加本周期流量。uptime 变小视为重启，计数器从 0 重新开始。
+fn account_traffic(
+    conn: &Connection,
+    token: &str,
+    metrics: &Map<String, Value>,
+    now: f64,
+) -> Result<Option<TrafficSummary>, AppError> {
+    let (sent, recv) = match (
+        metric_bytes(metrics, "total_sent_bytes", "total_sent"),
+        metric_bytes(metrics, "total_recv_bytes", "total_recv"),
+    ) {
+        (Some(s), Some(r)) => (s, r),
+        _ => return Ok(None),
+    };
+    let uptime = metrics.get("uptime").and_then(Value::as_u64).unwrap_or(0);
+    conn.execute(
+        "INSERT OR IGNORE INTO traffic_state (node_token) VALUES (?)",
+        params![token],
+    )?;
+    let (reset_day, last_sent, last_recv, last_uptime): (u32, Option<i64>, Option<i64>, Option<i64>) =
+        conn.query_row(
+            "SELECT reset_day, last_sent, last_recv, last_uptime FROM traffic_state WHERE node_token = ?",
+            params![token],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+    let (cycle_start, _) = billing_cycle(now, reset_day);
+    let (delta_sent, delta_recv) = match (last_sent, last_recv, last_uptime) {
+        (Some(ls), Some(lr), Some(lu)) if uptime >= lu as u64 => {
+            // 未重启时计数器变小（网卡被移除、统计范围变化或计数器回绕），本次增量无法得知，记为 0
+            if sent < ls as u64 || recv < lr as u64 {
+                warn!("traffic counters of {token} went backwards without a reboot (sent {ls} -> {sent}, recv {lr} -> {recv})");
+            }
+            (sent.saturating_sub(ls as u64), recv.saturating_sub(lr as u64))
+        }
+        // 重启后计数器清零，当前值即为开机以来的增量
+        (Some(_), Some(_), Some(_)) => (sent, recv),
+        // 首次上报：开机时间落在本周期内时才计入已有计数
+        _ if now - uptime as f64 >= cycle_start => (sent, recv),
+        _ => (0, 0),
+    };
+    conn.execute(
+        "UPDATE traffic_state SET last_sent = ?, last_recv = ?, last_uptime = ? WHERE node_token = ?",
+        params![sent as i64, recv as i64, uptime as i64, token],
+    )?;
+    conn.execute(
+        "INSERT INTO traffic_monthly (node_token, period, sent, recv) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(node_token, period) DO UPDATE SET sent = sent + ?3, recv = recv + ?4",
+        params![token, period_key(cycle_start), delta_sent as i64, delta_recv as i64],
+    )?;
+    traffic_summary(conn, token, now)
+}
+
+fn traffic_summary(conn: &Connection, token: &str, now: f64) -> Result<Option<TrafficSummary>, AppError> {
+    let state: Option<(u32, Option<i64>, String)> = conn
+        .query_row(
+            "SELECT reset_day, quota_bytes, quota_mode FROM traffic_state WHERE node_token = ?",
+            params![token],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .ok();
+    let (reset_day, quota_bytes, quota_mode) = match state {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let (cycle_start, cycle_end) = billing_cycle(now, reset_day);
+    let (sent, recv): (i64, i64) = conn
+        .query_row(
+            "SELECT sent, recv FROM traffic_monthly WHERE node_token = ? AND period = ?",
+            params![token, period_key(cycle_start)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap_or((0, 0));
+    let used = match quota_mode.as_str() {
+        "out" => sent,
+        "in" => recv,
+        "max" => sent.max(recv),
+        _ => sent + recv,
+    };
+    let elapsed = (now - cycle_start).max(1.0);
+    let projected = (used as f64 * (cycle_end - cycle_start) / elapsed) as i64;
+    let pct = |v: i64| {
+        quota_bytes
+            .filter(|q| *q > 0)
+            .map(|q| ((v as f64 / q as f64) * 10000.0).round() / 100.0)
+    };
+    Ok(Some(TrafficSummary {
+        reset_day,
+        quota_mode,
+        quota_bytes,
+        cycle_start,
+        cycle_end,
+        sent,
+        recv,
+        used,
+        usage_percent: pct(used),
+        projected,
+        projected_percent: pct(projected),
+    }))
+}
+
+fn traffic_history(conn: &Connection, token: &str) -> Result<Vec<TrafficPeriod>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT period, sent, recv FROM traffic_monthly WHERE node_token = ? ORDER BY period DESC LIMIT 24",
+    )?;
+    let rows = stmt.query_map(params![token], |row| {
+        Ok(TrafficPeriod {
+            period: row.get(0)?,
+            sent: row.get(1)?,
+            recv: row.get(2)?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+fn update_traffic_settings(db_path: &Path, token: &str, req: &UpdateTrafficRequest) -> Result<(), AppError> {
+    let conn = Connection::open(db_path)?;
+    let exists: bool = conn
+        .query_row("SELECT 1 FROM nodes WHERE token = ?", params![token], |_| Ok(true))
+        .unwrap_or(false);
+    if !exists {
+        return Err(AppError::NotFound);
+    }
+    conn.execute(
+        "INSERT OR IGNORE INTO traffic_state (node_token) VALUES (?)",
+        params![token],
+    )?;
+    if let Some(day) = req.reset_day {
+        if !(1..=28).contains(&day) {
+            return Err(AppError::BadRequest("reset_day must be 1-28".into()));
+        }
+        conn.execute(
+            "UPDATE traffic_state SET reset_day = ? WHERE node_token = ?",
+            params![day, token],
+        )?;
+    }
+    if let Some(gb) = req.quota_gb {
+        let quota = if gb > 0.0 { Some((gb * GB) as i64) } else { None };
+        conn.execute(
+            "UPDATE traffic_state SET quota_bytes = ? WHERE node_token = ?",
+            params![quota, token],
+        )?;
+    }
+    if let Some(mode) = req.quota_mode.as_deref() {
+        if !matches!(mode, "sum" | "out" | "in" | "max") {
+            return Err(AppError::BadRequest("quota_mode must be sum/out/in/max".into()));
+        }
+        conn.execute(
+            "UPDATE traffic_state SET quota_mode = ? WHERE node_token = ?",
+            params![mode, token],
+        )?;
+    }
+    Ok(())
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
 fn generate_token() -> String {
     let mut rng = rand::thread_rng();
     (0..40)
@@ -536,6 +2275,16 @@ fn auth_enabled(settings: &Settings) -> bool {
     settings.admin_user.is_some() && settings.admin_pass.is_some()
 }
 
+/// Agent 请求携带的 `Authorization: Bearer <token>`。
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
 fn require_auth(headers: &HeaderMap, settings: &Settings) -> Result<(), AppError> {
     if !auth_enabled(settings) {
         return Ok(());
@@ -670,3 +2419,270 @@ async fn get_background_file_handler(State(state): State<AppState>) -> Result<(H
     headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_str(mime.as_ref()).unwrap_or(header::HeaderValue::from_static("application/octet-stream")));
     Ok((headers, data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("imonitor-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        init_db(&path).unwrap();
+        path
+    }
+
+    fn listening(ports: &[(&str, &str, i64)]) -> Map<String, Value> {
+        let items: Vec<Value> = ports
+            .iter()
+            .map(|(proto, address, port)| json!({"proto": proto, "address": address, "port": port}))
+            .collect();
+        let mut metrics = Map::new();
+        metrics.insert("listening".into(), Value::Array(items));
+        metrics
+    }
+
+    fn event_kinds(db_path: &Path, token: &str) -> Vec<String> {
+        let mut kinds: Vec<String> = list_node_events(db_path, token)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.kind)
+            .collect();
+        kinds.reverse();
+        kinds
+    }
+
+    #[test]
+    fn listen_port_events_are_keyed_by_proto_and_port() {
+        let db_path = test_db("listen-ports");
+        let token = create_node(&db_path, None).unwrap().token;
+        let conn = Connection::open(&db_path).unwrap();
+        let track = |ports: &[(&str, &str, i64)], now: f64| {
+            track_listen_ports(&conn, &token, &listening(ports), now).unwrap();
+        };
+        track(&[("tcp", "0.0.0.0", 22)], 1.0);
+        // 换绑地址、同端口多地址都不产生事件
+        track(&[("tcp", "127.0.0.1", 22), ("tcp", "::1", 22)], 2.0);
+        assert!(event_kinds(&db_path, &token).is_empty());
+        // 只缺席一次的端口视为重启，不记关闭
+        track(&[], 3.0);
+        track(&[("tcp", "127.0.0.1", 22), ("tcp", "::1", 22)], 4.0);
+        assert!(event_kinds(&db_path, &token).is_empty());
+        track(&[("udp", "0.0.0.0", 53)], 5.0);
+        track(&[("udp", "0.0.0.0", 53)], 6.0);
+        assert_eq!(event_kinds(&db_path, &token), ["port_opened", "port_closed"]);
+    }
+
+    #[test]
+    fn watch_alerts_resolve_when_watch_is_no_longer_reported() {
+        let db_path = test_db("watch-alerts");
+        let token = create_node(&db_path, None).unwrap().token;
+        let conn = Connection::open(&db_path).unwrap();
+        let open_alerts = || -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM alert_events WHERE node_token = ? AND resolved_at IS NULL",
+                params![token],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let mut metrics = Map::new();
+        metrics.insert(
+            "watches".into(),
+            json!([
+                {"id": "w1", "name": "nginx", "status": "down"},
+                {"id": "w2", "name": "sshd", "status": "down"},
+            ]),
+        );
+        evaluate_watch_alerts(&conn, &token, &metrics, 1.0).unwrap();
+        assert_eq!(open_alerts(), 2);
+        metrics.insert("watches".into(), json!([{"id": "w2", "name": "sshd", "status": "down"}]));
+        evaluate_watch_alerts(&conn, &token, &metrics, 2.0).unwrap();
+        assert_eq!(open_alerts(), 1);
+        evaluate_watch_alerts(&conn, &token, &Map::new(), 3.0).unwrap();
+        assert_eq!(open_alerts(), 0);
+    }
+
+    #[test]
+    fn agent_config_version_is_a_stable_digest() {
+        let db_path = test_db("agent-config");
+        let token = create_node(&db_path, None).unwrap().token;
+        let settings = json!({"interval": 5, "collectors": {"containers": false}});
+        let req = PutAgentConfigRequest {
+            node_token: None,
+            tag: None,
+            settings: settings.as_object().cloned().unwrap(),
+        };
+        put_agent_config(&db_path, &req).unwrap();
+        let conn = Connection::open(&db_path).unwrap();
+        let (version, merged) = effective_agent_config(&conn, &token).unwrap();
+        // sha256('{"collectors":{"containers":false},"interval":5}') 的前 8 字节
+        assert_eq!(version, "c7f03ec40fdf0958");
+        assert_eq!(Value::Object(merged), settings);
+    }
+
+    #[test]
+    fn custom_history_averages_per_minute() {
+        let db_path = test_db("custom-history");
+        let conn = Connection::open(&db_path).unwrap();
+        let custom = |v: f64| {
+            let mut metrics = Map::new();
+            metrics.insert("custom".into(), json!({"queue.depth": v}));
+            metrics
+        };
+        // 时间戳需在保留期内，否则会被顺带清理掉
+        let minute = (unix_now() / 60.0).floor() * 60.0 - 120.0;
+        record_history(&conn, "n1", &custom(10.0), minute).unwrap();
+        record_history(&conn, "n1", &custom(20.0), minute + 30.0).unwrap();
+        record_history(&conn, "n1", &custom(60.0), minute + 59.0).unwrap();
+        record_history(&conn, "n1", &custom(5.0), minute + 61.0).unwrap();
+        let series = load_custom_history(&db_path, "n1", 0.0, None).unwrap();
+        assert_eq!(series["queue.depth"], json!([[minute, 30.0], [minute + 60.0, 5.0]]));
+    }
+
+    #[test]
+    fn agent_config_is_looked_up_by_bearer_token() {
+        let db_path = test_db("agent-config-bearer");
+        let token = create_node(&db_path, None).unwrap().token;
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        let bearer = bearer_token(&headers).unwrap();
+        assert_eq!(bearer, token);
+        assert!(node_agent_config(&db_path, &bearer).unwrap().get("version").is_some());
+        assert!(matches!(node_agent_config(&db_path, "unknown"), Err(AppError::NotFound)));
+    }
+
+    fn day(y: i64, m: u32, d: u32) -> f64 {
+        days_from_civil(y, m, d) as f64 * 86400.0
+    }
+
+    #[test]
+    fn civil_date_conversion_round_trips() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2023, 2, 28) + 1), (2023, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 12, 31) + 1), (2025, 1, 1));
+    }
+
+    #[test]
+    fn billing_cycle_rolls_over_months_and_years() {
+        assert_eq!(billing_cycle(day(2024, 12, 20) + 3600.0, 15), (day(2024, 12, 15), day(2025, 1, 15)));
+        assert_eq!(billing_cycle(day(2025, 1, 10), 15), (day(2024, 12, 15), day(2025, 1, 15)));
+        // 周期从重置日 0 点开始
+        assert_eq!(billing_cycle(day(2025, 1, 15), 15), (day(2025, 1, 15), day(2025, 2, 15)));
+        assert_eq!(billing_cycle(day(2025, 3, 1), 1), (day(2025, 3, 1), day(2025, 4, 1)));
+    }
+
+    #[test]
+    fn billing_cycle_clamps_reset_day_to_28() {
+        assert_eq!(billing_cycle(day(2024, 3, 27), 28), (day(2024, 2, 28), day(2024, 3, 28)));
+        // 29~31 日按 28 日处理，短月也有固定的周期边界
+        for reset_day in 29..=31 {
+            assert_eq!(billing_cycle(day(2024, 2, 29), reset_day), (day(2024, 2, 28), day(2024, 3, 28)));
+            assert_eq!(billing_cycle(day(2023, 2, 27), reset_day), (day(2023, 1, 28), day(2023, 2, 28)));
+        }
+    }
+
+    #[test]
+    fn traffic_accounting_handles_reboots_and_counter_drops() {
+        let db_path = test_db("traffic");
+        let conn = Connection::open(&db_path).unwrap();
+        let report = |sent: u64, recv: u64, uptime: u64, now: f64| {
+            let mut metrics = Map::new();
+            metrics.insert("total_sent_bytes".into(), json!(sent));
+            metrics.insert("total_recv_bytes".into(), json!(recv));
+            metrics.insert("uptime".into(), json!(uptime));
+            let summary = account_traffic(&conn, "n1", &metrics, now).unwrap().unwrap();
+            (summary.sent, summary.recv)
+        };
+        let now = day(2025, 1, 10);
+        // 开机早于本周期，首次上报的计数不计入
+        assert_eq!(report(1000, 2000, 20 * 86400, now), (0, 0));
+        assert_eq!(report(1500, 2600, 20 * 86400 + 60, now + 60.0), (500, 600));
+        // 重启后计数器从 0 开始，当前值即为增量
+        assert_eq!(report(100, 200, 30, now + 120.0), (600, 800));
+        // 未重启时计数器变小，本次增量记为 0，之后以新值为基准
+        assert_eq!(report(50, 150, 90, now + 180.0), (600, 800));
+        assert_eq!(report(80, 190, 150, now + 240.0), (630, 840));
+        // 进入下一个周期后重新累计
+        assert_eq!(report(100, 200, 150 + 86400 * 30, day(2025, 2, 1) + 60.0), (20, 10));
+    }
+
+    fn encoded_headers(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn decode_body_handles_each_encoding() {
+        let raw = br#"[{"token":"t"}]"#.to_vec();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, &raw).unwrap();
+        let gzip = gz.finish().unwrap();
+        let zstd = zstd::encode_all(&raw[..], 3).unwrap();
+        assert_eq!(decode_body(&HeaderMap::new(), &raw).unwrap(), raw);
+        assert_eq!(decode_body(&encoded_headers("gzip"), &gzip).unwrap(), raw);
+        assert_eq!(decode_body(&encoded_headers("X-Gzip"), &gzip).unwrap(), raw);
+        assert_eq!(decode_body(&encoded_headers("zstd"), &zstd).unwrap(), raw);
+        assert!(matches!(decode_body(&encoded_headers("br"), &raw), Err(AppError::BadRequest(_))));
+        assert!(matches!(decode_body(&encoded_headers("gzip"), &raw), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn decode_body_rejects_output_over_the_limit() {
+        let bomb = zstd::encode_all(&vec![0u8; MAX_DECODED_BODY as usize + 1][..], 3).unwrap();
+        assert!(bomb.len() < 64 * 1024);
+        let err = decode_body(&encoded_headers("zstd"), &bomb).err().unwrap();
+        assert_eq!(err.to_string(), AppError::BadRequest("decoded body too large".into()).to_string());
+        let exact = zstd::encode_all(&vec![0u8; MAX_DECODED_BODY as usize][..], 3).unwrap();
+        assert_eq!(decode_body(&encoded_headers("zstd"), &exact).unwrap().len() as u64, MAX_DECODED_BODY);
+    }
+
+    fn report_headers(content_type: &str, encoding: &str) -> HeaderMap {
+        let mut headers = encoded_headers(encoding);
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn decode_report_follows_content_type() {
+        let report = serde_json::json!({"token": "t", "cpu": 12.5, "disks": [{"name": "sda"}]});
+        for (mime, format) in [
+            ("application/json; charset=utf-8", WireFormat::Json),
+            ("application/cbor", WireFormat::Cbor),
+            ("application/x-msgpack", WireFormat::MsgPack),
+        ] {
+            let raw = format.encode(&report).unwrap();
+            let decoded: Value = decode_report(&report_headers(mime, "identity"), &raw).unwrap();
+            assert_eq!(decoded, report, "{mime}");
+        }
+        let packed = zstd::encode_all(&WireFormat::MsgPack.encode(&report).unwrap()[..], 3).unwrap();
+        let decoded: Value = decode_report(&report_headers("application/msgpack", "zstd"), &packed).unwrap();
+        assert_eq!(decoded, report);
+        let plain: Value = decode_report(&HeaderMap::new(), br#"{"token":"t"}"#).unwrap();
+        assert_eq!(plain["token"], "t");
+        let err = decode_report::<Value>(&report_headers("text/plain", "identity"), b"x").err().unwrap();
+        assert!(matches!(err, AppError::UnsupportedMediaType(_)));
+        let err = decode_report::<Value>(&report_headers("application/cbor", "identity"), b"{").err().unwrap();
+        assert!(matches!(err, AppError::BadRequest(_)));
+    }
+
+    #[test]
+    fn encode_reply_picks_first_supported_accept_type() {
+        let reply = serde_json::json!({"ok": true});
+        let mut headers = HeaderMap::new();
+        let content_type = |headers: &HeaderMap| {
+            let response = encode_reply(headers, &reply).unwrap();
+            response.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string()
+        };
+        assert_eq!(content_type(&headers), "application/json");
+        headers.insert(header::ACCEPT, "text/html, application/cbor, application/json".parse().unwrap());
+        assert_eq!(content_type(&headers), "application/cbor");
+        headers.insert(header::ACCEPT, "application/vnd.msgpack".parse().unwrap());
+        assert_eq!(content_type(&headers), "application/msgpack");
+        headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+        assert_eq!(content_type(&headers), "application/json");
+    }
+}